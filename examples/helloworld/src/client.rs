@@ -1,3 +1,4 @@
+use grpc_web::{GrpcWebClientService, Mode};
 use hello_world::greeter_client::GreeterClient;
 use hello_world::HelloRequest;
 
@@ -7,17 +8,22 @@ pub mod hello_world {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut client = GreeterClient::connect("http://[::1]:50052").await?;
-
     let request = tonic::Request::new(HelloRequest {
         name: "Tonic".into(),
     });
 
     println!("REQUEST={:?}", request);
 
-    let response = client.say_hello(request).await?;
+    let response = if std::env::args().any(|arg| arg == "--grpc-web") {
+        // talk to the proxy the way a browser would
+        let service = GrpcWebClientService::new("http://[::1]:8080".parse()?, Mode::Text);
+        GreeterClient::new(service).say_hello(request).await?
+    } else {
+        let mut client = GreeterClient::connect("http://[::1]:50052").await?;
+        client.say_hello(request).await?
+    };
 
     println!("RESPONSE={:?}", response);
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,4 +1,8 @@
+use grpc_web::{Cors, GrpcWebLayer};
+use hyper::service::make_service_fn;
+use std::convert::Infallible;
 use tonic::transport::Server;
+use tower::Layer;
 use tonic::{Request, Response, Status};
 use tokio::sync::mpsc;
 use std::pin::Pin;
@@ -64,8 +68,29 @@ impl proto::greeter_server::Greeter for MyGreeter {
 //     Ok(req)
 // }
 
+// Serve gRPC-Web from this process, without the proxy.
+async fn serve_grpc_web(greeter: MyGreeter) -> Result<(), Box<dyn std::error::Error>> {
+    let addr = "[::1]:8080".parse().unwrap();
+    // NOTE: the page is not served from this origin, so it needs CORS
+    let service = GrpcWebLayer::new()
+        .cors(Cors::default())
+        .layer(proto::greeter_server::GreeterServer::new(greeter));
+
+    let make_svc = make_service_fn(move |_| {
+        let service = service.clone();
+        async move { Ok::<_, Infallible>(service) }
+    });
+    hyper::Server::bind(&addr).serve(make_svc).await?;
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if std::env::args().any(|arg| arg == "--grpc-web") {
+        return serve_grpc_web(MyGreeter::default()).await;
+    }
+
     let service = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
         .build()
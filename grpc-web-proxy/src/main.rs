@@ -1,32 +1,363 @@
 use clap::Clap;
-use grpc_web::Error;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{http::HeaderValue, Server};
+use grpc_web::{
+    unix_socket_uri, Cors, Discovery, DnsDiscovery, FileDiscovery, GrpcWebProxy, HeaderFilter,
+    LoadBalancing, Metadata, ServicePattern, TimeoutPolicy, Timeouts, UpstreamTls,
+};
+use hyper::http::Uri;
+use hyper::service::make_service_fn;
+use hyper::Server;
+use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tls::Certificates;
+use tokio::net::TcpListener;
 
-use proxy::{HttpConfig, Proxy};
+mod tls;
+#[cfg(unix)]
+mod unix;
 
-mod proxy;
+/// Pause after failing to accept a connection, e.g. when out of file
+/// descriptors, rather than retrying straight away.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
 
 /// Simple gRPC-Web proxy, built in Rust.
 #[derive(Clap)]
 #[clap(version = "0.1", author = "Gregory Hill <gregorydhill@outlook.com>")]
 struct Opts {
-    /// Address to forward grpc requests to.
-    #[clap(long, default_value = "http://[::1]:50052")]
-    grpc_addr: String,
+    /// Comma separated list of replicas to forward grpc requests to, which
+    /// may listen on a unix socket at unix:///PATH. Replicas may also be
+    /// discovered from the A/AAAA records of dns:///HOST:PORT, the SRV
+    /// records of srv:///NAME, or a JSON or TOML file at file:///PATH
+    /// listing "endpoints".
+    #[clap(long, default_value = "http://[::1]:50052", use_delimiter = true)]
+    grpc_addr: Vec<Addr>,
 
-    /// Address to bind this proxy server to.
+    /// Address to forward calls to some services to instead, as
+    /// PATTERN=ADDR[,ADDR...], e.g. helloworld.*=http://[::1]:50053.
+    /// Patterns are fully-qualified service names, which may contain a `*`
+    /// wildcard, and are tried in order.
+    #[clap(long)]
+    route: Vec<Route>,
+
+    /// How often to discover replicas again, e.g. 30s.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    discovery_interval: Option<Duration>,
+
+    /// How calls are spread across replicas, either round-robin or p2c
+    /// (power of two choices).
+    #[clap(long, default_value = "round-robin")]
+    load_balancing: LoadBalancing,
+
+    /// How often to check the health of every replica, e.g. 5s. Replicas
+    /// are taken out of rotation while grpc.health.v1.Health reports them
+    /// as not serving.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    health_check_interval: Option<Duration>,
+
+    /// Connect to every upstream over TLS, verifying them with the public
+    /// certificate authorities unless --upstream-ca is given. Implied by the
+    /// other upstream TLS options.
+    #[clap(long)]
+    upstream_tls: bool,
+
+    /// PEM encoded CA certificates to verify upstreams with.
+    #[clap(long)]
+    upstream_ca: Option<PathBuf>,
+
+    /// PEM encoded client certificate chain to present to upstreams.
+    #[clap(long, requires = "upstream-key")]
+    upstream_cert: Option<PathBuf>,
+
+    /// PEM encoded private key of the client certificate.
+    #[clap(long, requires = "upstream-cert")]
+    upstream_key: Option<PathBuf>,
+
+    /// Name to send as SNI and expect in upstream certificates, instead of
+    /// the host of each replica.
+    #[clap(long)]
+    upstream_domain_name: Option<String>,
+
+    /// Accept upstream certificates issued for any name. Only meant for
+    /// development.
+    #[clap(long)]
+    upstream_skip_domain_verification: bool,
+
+    /// Address to bind this proxy server to, or unix:///PATH to listen on a
+    /// unix socket.
     #[clap(long, default_value = "[::1]:8080")]
     host_addr: String,
 
-    /// Comma separated list of allowed origins.
-    #[clap(long, default_value = "*")]
-    allowed_cors_domains: HeaderValue,
+    /// PEM encoded certificate chain to serve HTTPS with. Reloaded when the
+    /// file changes or on SIGHUP.
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM encoded private key of the certificate, in PKCS#8 or RSA format.
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Comma separated list of allowed origins, which may contain a `*`
+    /// wildcard (e.g. https://*.example.com).
+    #[clap(long, default_value = "*", use_delimiter = true)]
+    allowed_cors_domains: Vec<String>,
 
     /// Comma separated list of allowed headers.
-    #[clap(long, default_value = "*")]
-    allowed_cors_headers: HeaderValue,
+    #[clap(long, default_value = "*", use_delimiter = true)]
+    allowed_cors_headers: Vec<String>,
+
+    /// Comma separated list of response headers browsers may read, in
+    /// addition to the grpc-* headers.
+    #[clap(long, use_delimiter = true)]
+    exposed_cors_headers: Vec<String>,
+
+    /// Allow requests with credentials such as cookies, which requires
+    /// --allowed-cors-domains to list the origins to allow.
+    #[clap(long)]
+    allow_cors_credentials: bool,
+
+    /// How long browsers may cache preflight responses, e.g. 10m.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    cors_max_age: Option<Duration>,
+
+    /// Comma separated list of request headers to forward as metadata,
+    /// names may end in `*` to match a prefix. Forwards all if empty.
+    #[clap(long, use_delimiter = true)]
+    forward_headers: Vec<String>,
+
+    /// Comma separated list of request headers never to forward.
+    #[clap(long, use_delimiter = true)]
+    drop_headers: Vec<String>,
+
+    /// Timeout for calls without a grpc-timeout, e.g. 30s.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    default_timeout: Option<Duration>,
+
+    /// Longest timeout a client may ask for, e.g. 5m.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    max_timeout: Option<Duration>,
+
+    /// Largest message accepted from clients or upstreams once
+    /// decompressed, in bytes.
+    #[clap(long)]
+    max_message_size: Option<usize>,
+
+    /// Timeouts for a single method, as PATH=[DEFAULT][:MAX],
+    /// e.g. /helloworld.Greeter/SayRepeatHello=1m:10m.
+    #[clap(long)]
+    method_timeout: Vec<MethodTimeout>,
+
+    /// Serialized FileDescriptorSet describing the upstream services,
+    /// instead of asking its reflection service.
+    #[clap(long)]
+    descriptor_set: Vec<PathBuf>,
+
+    /// .proto file describing upstream services, instead of asking its
+    /// reflection service.
+    #[clap(long)]
+    proto: Vec<PathBuf>,
+
+    /// How often to reflect the upstream again, e.g. 5m. The upstream is
+    /// also reflected again on SIGHUP.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    refresh_interval: Option<Duration>,
+
+    /// Shortest time between refreshes caused by calls to unknown
+    /// methods, e.g. 10s.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    miss_refresh_interval: Option<Duration>,
+
+    /// Forward calls to methods the proxy does not know of, assuming they
+    /// are unary or server streaming. Allows upstreams without reflection
+    /// or descriptors.
+    #[clap(long)]
+    forward_unknown_methods: bool,
+
+    /// Path answering GET requests with 200 once the upstream services are
+    /// known, and 503 until then, e.g. /ready.
+    #[clap(long)]
+    readiness_path: Option<String>,
+}
+
+/// Describe the upstream services from the given files, if any.
+fn load_metadata(descriptor_sets: &[PathBuf], protos: &[PathBuf]) -> Option<Metadata> {
+    if descriptor_sets.is_empty() && protos.is_empty() {
+        return None;
+    }
+
+    let descriptor_sets = descriptor_sets.iter().map(|path| {
+        Metadata::from_file_descriptor_set_path(path)
+            .unwrap_or_else(|err| panic!("Unable to load {}: {}", path.display(), err))
+    });
+    let protos = protos.iter().map(|path| {
+        Metadata::from_proto_path(path)
+            .unwrap_or_else(|err| panic!("Unable to load {}: {}", path.display(), err))
+    });
+    Some(
+        descriptor_sets
+            .chain(protos)
+            .fold(Metadata::default(), Metadata::merge),
+    )
+}
+
+/// TLS settings for the upstreams, if any upstream TLS option was given.
+fn load_upstream_tls(opts: &Opts) -> Option<UpstreamTls> {
+    let read = |path: &PathBuf| {
+        std::fs::read(path)
+            .unwrap_or_else(|err| panic!("Unable to load {}: {}", path.display(), err))
+    };
+
+    if !(opts.upstream_tls
+        || opts.upstream_ca.is_some()
+        || opts.upstream_cert.is_some()
+        || opts.upstream_domain_name.is_some()
+        || opts.upstream_skip_domain_verification)
+    {
+        return None;
+    }
+    let mut tls =
+        UpstreamTls::new().skip_domain_verification(opts.upstream_skip_domain_verification);
+    if let Some(ca) = &opts.upstream_ca {
+        tls = tls.ca_certificate(read(ca));
+    }
+    if let (Some(cert), Some(key)) = (&opts.upstream_cert, &opts.upstream_key) {
+        tls = tls.identity(read(cert), read(key));
+    }
+    if let Some(domain_name) = &opts.upstream_domain_name {
+        tls = tls.domain_name(domain_name.clone());
+    }
+    Some(tls)
+}
+
+/// Parse durations such as `500ms`, `10s`, `5m` or `1h`.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("Missing unit in duration: {}", value))?;
+    let (amount, unit) = value.split_at(split);
+    let invalid = || format!("Invalid duration: {}", value);
+    let amount: u64 = amount
+        .parse()
+        .ok()
+        .filter(|&amount| amount > 0)
+        .ok_or_else(invalid)?;
+
+    match unit {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "s" => Ok(Duration::from_secs(amount)),
+        "m" => amount
+            .checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or_else(invalid),
+        "h" => amount
+            .checked_mul(3600)
+            .map(Duration::from_secs)
+            .ok_or_else(invalid),
+        _ => Err(format!("Invalid unit in duration: {}", value)),
+    }
+}
+
+/// Replica, or where to discover replicas.
+enum Addr {
+    Replica(Uri),
+    Discovery(Arc<dyn Discovery>),
+}
+
+impl FromStr for Addr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let discovery: Arc<dyn Discovery> = if let Some(addr) = value.strip_prefix("dns:///") {
+            let mut parts = addr.rsplitn(2, ':');
+            let port = parts
+                .next()
+                .and_then(|port| port.parse().ok())
+                .ok_or_else(|| format!("Expected dns:///HOST:PORT: {}", value))?;
+            let host = parts
+                .next()
+                .ok_or_else(|| format!("Expected dns:///HOST:PORT: {}", value))?;
+            Arc::new(DnsDiscovery::host(host, port).map_err(|err| err.to_string())?)
+        } else if let Some(name) = value.strip_prefix("srv:///") {
+            Arc::new(DnsDiscovery::srv(name).map_err(|err| err.to_string())?)
+        } else if let Some(path) = value.strip_prefix("file://") {
+            Arc::new(FileDiscovery::new(path))
+        } else if let Some(path) = value.strip_prefix("unix://") {
+            return unix_socket_uri(path)
+                .map(Addr::Replica)
+                .map_err(|err| err.to_string());
+        } else {
+            return value
+                .parse()
+                .map(Addr::Replica)
+                .map_err(|err| format!("Invalid address {}: {}", value, err));
+        };
+        Ok(Addr::Discovery(discovery))
+    }
+}
+
+struct Route(ServicePattern, Vec<Addr>);
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parts = value.splitn(2, '=');
+        let pattern = ServicePattern::new(parts.next().unwrap_or_default());
+        let addrs = parts
+            .next()
+            .ok_or_else(|| format!("Expected PATTERN=ADDR[,ADDR...]: {}", value))?
+            .split(',')
+            .map(Addr::from_str)
+            .collect::<Result<_, _>>()?;
+        Ok(Self(pattern, addrs))
+    }
+}
+
+struct MethodTimeout(String, TimeoutPolicy);
+
+impl FromStr for MethodTimeout {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parts = value.splitn(2, '=');
+        let path = parts.next().unwrap_or_default().to_string();
+        let mut timeouts = parts
+            .next()
+            .ok_or_else(|| format!("Expected PATH=[DEFAULT][:MAX]: {}", value))?
+            .splitn(2, ':')
+            .map(|timeout| match timeout {
+                "" => Ok(None),
+                timeout => parse_duration(timeout).map(Some),
+            });
+
+        let default = timeouts.next().transpose()?.flatten();
+        let max = timeouts.next().transpose()?.flatten();
+        Ok(Self(path, TimeoutPolicy { default, max }))
+    }
+}
+
+/// Reload the certificate if any, and reflect the upstream again, whenever
+/// SIGHUP is received.
+#[cfg(unix)]
+fn refresh_on_hangup(proxy: GrpcWebProxy, certificates: Option<Arc<Certificates>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).expect("Unable to listen for SIGHUP");
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            log::info!("Received SIGHUP, reloading");
+            if let Some(certificates) = &certificates {
+                if let Err(err) = certificates.reload() {
+                    log::warn!("Unable to reload certificate: {}", err);
+                }
+            }
+            if let Err(err) = proxy.refresh_metadata().await {
+                log::warn!("Unable to refresh metadata: {}", err);
+            }
+        }
+    });
 }
 
 #[tokio::main]
@@ -34,33 +365,111 @@ async fn main() {
     env_logger::init();
     let opts: Opts = Opts::parse();
 
-    let proxy = Proxy::new(
-        opts.grpc_addr,
-        HttpConfig {
-            allowed_cors_domains: opts.allowed_cors_domains,
-            allowed_cors_headers: opts.allowed_cors_headers,
-        },
-    )
-    .await
-    .expect("Unable to start proxy");
+    let mut builder = GrpcWebProxy::builder();
+    if let Some(metadata) = load_metadata(&opts.descriptor_set, &opts.proto) {
+        builder = builder.metadata(metadata);
+    }
+    if let Some(tls) = load_upstream_tls(&opts) {
+        builder = builder.tls(tls);
+    }
+    if let Some(size) = opts.max_message_size {
+        builder = builder.max_message_size(size);
+    }
+    if let Some(interval) = opts.refresh_interval {
+        builder = builder.refresh_interval(interval);
+    }
+    if let Some(interval) = opts.miss_refresh_interval {
+        builder = builder.miss_refresh_interval(interval);
+    }
+    if let Some(path) = opts.readiness_path {
+        builder = builder.readiness_path(path);
+    }
+    if let Some(interval) = opts.health_check_interval {
+        builder = builder.health_check_interval(interval);
+    }
+    if let Some(interval) = opts.discovery_interval {
+        builder = builder.discovery_interval(interval);
+    }
+    for Route(pattern, addrs) in opts.route {
+        for addr in addrs {
+            builder = match addr {
+                Addr::Replica(uri) => builder.route(pattern.clone(), uri),
+                Addr::Discovery(discovery) => builder.route_discovery(pattern.clone(), discovery),
+            };
+        }
+    }
+    for addr in opts.grpc_addr {
+        builder = match addr {
+            Addr::Replica(uri) => builder.replica(uri),
+            Addr::Discovery(discovery) => builder.discovery(discovery),
+        };
+    }
+
+    let allows_any_origin = opts.allowed_cors_domains.iter().any(|origin| origin == "*");
+    assert!(
+        !(opts.allow_cors_credentials && allows_any_origin),
+        "--allow-cors-credentials cannot be combined with allowing any origin"
+    );
+    let proxy = builder
+        .cors(Cors::new(
+            opts.allowed_cors_domains,
+            opts.allowed_cors_headers,
+            opts.exposed_cors_headers,
+            opts.allow_cors_credentials,
+            opts.cors_max_age,
+        ))
+        .header_filter(HeaderFilter::new(opts.forward_headers, opts.drop_headers))
+        .forward_unknown_methods(opts.forward_unknown_methods)
+        .load_balancing(opts.load_balancing)
+        .timeouts(opts.method_timeout.into_iter().fold(
+            Timeouts::new(TimeoutPolicy {
+                default: opts.default_timeout,
+                max: opts.max_timeout,
+            }),
+            |timeouts, MethodTimeout(path, policy)| timeouts.with_method(path, policy),
+        ))
+        .build()
+        .expect("Invalid upstream");
+
+    let certificates = match (opts.tls_cert, opts.tls_key) {
+        (Some(cert), Some(key)) => {
+            let certificates = Certificates::load(cert, key).expect("Invalid tls_cert or tls_key");
+            certificates.reload_every(tls::RELOAD_INTERVAL);
+            Some(certificates)
+        }
+        _ => None,
+    };
+
+    #[cfg(unix)]
+    refresh_on_hangup(proxy.clone(), certificates.clone());
+
+    #[cfg(unix)]
+    {
+        if let Some(path) = opts.host_addr.strip_prefix("unix://") {
+            assert!(
+                certificates.is_none(),
+                "TLS is not supported on unix sockets"
+            );
+            let listener = unix::bind(path.as_ref())
+                .unwrap_or_else(|err| panic!("Unable to bind {}: {}", path, err));
+            unix::serve(listener, proxy).await;
+            return;
+        }
+    }
 
     let addr: SocketAddr = opts.host_addr.parse().expect("Invalid host_addr");
 
+    if let Some(certificates) = certificates {
+        let listener = TcpListener::bind(addr)
+            .await
+            .unwrap_or_else(|err| panic!("Unable to bind {}: {}", addr, err));
+        tls::serve(listener, certificates.acceptor(), proxy).await;
+        return;
+    }
+
     let make_svc = make_service_fn(move |_| {
         let proxy = proxy.clone();
-
-        async move {
-            Ok::<_, Error>(service_fn(move |req| {
-                let mut proxy = proxy.clone();
-                async move {
-                    let result = proxy.handle_http_request(req).await;
-                    if let Err(ref err) = result {
-                        eprintln!("{:?}", err);
-                    }
-                    result
-                }
-            }))
-        }
+        async move { Ok::<_, Infallible>(proxy) }
     });
 
     let server = Server::bind(&addr).serve(make_svc);
@@ -0,0 +1,70 @@
+use grpc_web::GrpcWebProxy;
+use hyper::server::conn::Http;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use tokio::net::UnixListener;
+
+/// Listen on the socket at `path`, replacing one left behind by a previous
+/// run.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    UnixListener::bind(path)
+}
+
+/// Serve the proxy to every connection accepted by `listener`, over HTTP/1.1
+/// or HTTP/2.
+pub async fn serve(listener: UnixListener, proxy: GrpcWebProxy) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                log::warn!("Unable to accept connection: {}", err);
+                tokio::time::sleep(crate::ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let connection = Http::new().serve_connection(stream, proxy.clone());
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                log::debug!("Connection failed: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::http::{Request, StatusCode};
+    use hyper::Body;
+    use tokio::net::UnixStream;
+
+    #[tokio::test]
+    async fn should_serve_unix_socket() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("grpc-web-proxy-{}.sock", std::process::id()));
+        // NOTE: e.g. left behind by a proxy which was killed
+        drop(std::os::unix::net::UnixListener::bind(&path)?);
+
+        let proxy = GrpcWebProxy::builder()
+            .readiness_path("/ready")
+            .connect_lazy("http://127.0.0.1:1")
+            .unwrap();
+        tokio::spawn(serve(bind(&path)?, proxy));
+
+        let (mut sender, connection) =
+            hyper::client::conn::handshake(UnixStream::connect(&path).await?)
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let request = Request::get("/ready").body(Body::empty()).unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        std::fs::remove_file(path)
+    }
+}
@@ -5,6 +5,13 @@ fn main() {
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     tonic_build::configure()
         .file_descriptor_set_path(out_dir.join("reflection_descriptor.bin"))
-        .compile(&["proto/reflection.proto"], &["proto"])
+        .compile(
+            &[
+                "proto/reflection_v1.proto",
+                "proto/reflection.proto",
+                "proto/health.proto",
+            ],
+            &["proto"],
+        )
         .unwrap();
 }
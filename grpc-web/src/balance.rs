@@ -0,0 +1,466 @@
+use crate::channel::{connect_lazy, UpstreamChannel};
+use crate::Error;
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use futures::{ready, Stream};
+use hyper::http::Uri;
+use hyper::Body;
+use rand::Rng;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tonic::transport::Endpoint;
+use tonic::Code;
+
+use proto::health_check_response::ServingStatus;
+use proto::health_client::HealthClient;
+use proto::HealthCheckRequest;
+
+pub(crate) mod proto {
+    tonic::include_proto!("grpc.health.v1");
+}
+
+/// How calls are spread across the replicas of an upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoadBalancing {
+    /// Each healthy replica in turn.
+    #[default]
+    RoundRobin,
+    /// Whichever of two randomly chosen healthy replicas has fewer calls in
+    /// flight.
+    PowerOfTwoChoices,
+}
+
+impl FromStr for LoadBalancing {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "round-robin" => Ok(LoadBalancing::RoundRobin),
+            "p2c" | "power-of-two-choices" => Ok(LoadBalancing::PowerOfTwoChoices),
+            _ => Err(format!("Unknown load balancing: {}", value)),
+        }
+    }
+}
+
+/// Replica of an upstream, which is only picked while healthy.
+pub(crate) struct Replica {
+    uri: Uri,
+    channel: UpstreamChannel,
+    healthy: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+impl Replica {
+    fn new(uri: Uri, channel: UpstreamChannel) -> Arc<Self> {
+        Arc::new(Self {
+            uri,
+            channel,
+            healthy: AtomicBool::new(true),
+            in_flight: AtomicUsize::new(0),
+        })
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                log::info!("{} is healthy, adding it to the rotation", self.uri);
+            } else {
+                log::warn!("{} is unhealthy, removing it from the rotation", self.uri);
+            }
+        }
+    }
+
+    /// Ask the replica for the overall health of its server. Replicas
+    /// without a health service are assumed to be serving.
+    async fn check(&self) -> bool {
+        let request = HealthCheckRequest {
+            service: String::new(),
+        };
+        match HealthClient::new(self.channel.clone()).check(request).await {
+            Ok(response) => response.into_inner().status == ServingStatus::Serving as i32,
+            Err(status) if status.code() == Code::Unimplemented => true,
+            Err(status) => {
+                log::debug!("Health check of {} failed: {}", self.uri, status);
+                false
+            }
+        }
+    }
+}
+
+/// Replica picked for a call, which counts as in flight until dropped.
+pub(crate) struct Pick(Arc<Replica>);
+
+impl Pick {
+    fn new(replica: Arc<Replica>) -> Self {
+        replica.in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(replica)
+    }
+
+    pub(crate) fn channel(&self) -> UpstreamChannel {
+        self.0.channel.clone()
+    }
+
+    /// Keep the replica picked until `body` has been read to the end or
+    /// dropped, so that streaming calls count as in flight throughout.
+    pub(crate) fn hold_until_end(self, body: Body) -> Body {
+        Body::wrap_stream(PickedBody {
+            body,
+            pick: Some(self),
+        })
+    }
+}
+
+impl Drop for Pick {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct PickedBody {
+    body: Body,
+    pick: Option<Pick>,
+}
+
+impl Stream for PickedBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = ready!(Pin::new(&mut self.body).poll_next(cx));
+        if item.is_none() {
+            self.pick = None;
+        }
+        Poll::Ready(item)
+    }
+}
+
+/// Spreads calls across the healthy replicas of an upstream, which may be
+/// replaced as they are discovered.
+pub(crate) struct Balancer {
+    replicas: ArcSwap<Vec<Arc<Replica>>>,
+    load_balancing: LoadBalancing,
+    next: AtomicUsize,
+    /// Set once health checks have started, for replicas added later.
+    health_check_interval: Mutex<Option<Duration>>,
+}
+
+impl Balancer {
+    /// Replicas start out healthy, until a health check says otherwise.
+    pub(crate) fn new(
+        replicas: Vec<(Uri, UpstreamChannel)>,
+        load_balancing: LoadBalancing,
+    ) -> Self {
+        Self {
+            replicas: ArcSwap::from_pointee(
+                replicas
+                    .into_iter()
+                    .map(|(uri, channel)| Replica::new(uri, channel))
+                    .collect(),
+            ),
+            load_balancing,
+            next: AtomicUsize::new(0),
+            health_check_interval: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn pick(&self) -> Result<Pick, Error> {
+        let replicas = self.replicas.load();
+        let healthy: Vec<_> = replicas
+            .iter()
+            .filter(|replica| replica.is_healthy())
+            .collect();
+        let replica = match (self.load_balancing, healthy.len()) {
+            (_, 0) => return Err(Error::NoHealthyReplicas),
+            (_, 1) => healthy[0],
+            (LoadBalancing::RoundRobin, len) => {
+                healthy[self.next.fetch_add(1, Ordering::Relaxed) % len]
+            }
+            (LoadBalancing::PowerOfTwoChoices, len) => {
+                let mut rng = rand::thread_rng();
+                let first = rng.gen_range(0..len);
+                let mut second = rng.gen_range(0..len - 1);
+                if second >= first {
+                    second += 1;
+                }
+                let (first, second) = (healthy[first], healthy[second]);
+                if second.in_flight.load(Ordering::Relaxed)
+                    < first.in_flight.load(Ordering::Relaxed)
+                {
+                    second
+                } else {
+                    first
+                }
+            }
+        };
+        Ok(Pick::new(replica.clone()))
+    }
+
+    /// Check the health of every replica each `interval` in the background,
+    /// until the balancer has been dropped. Checks which take longer than
+    /// `interval` count as failed.
+    pub(crate) fn health_check_every(&self, interval: Duration) -> Vec<JoinHandle<()>> {
+        *self.health_check_interval.lock().unwrap() = Some(interval);
+        self.replicas
+            .load()
+            .iter()
+            .map(|replica| tokio::spawn(health_check(Arc::downgrade(replica), interval)))
+            .collect()
+    }
+
+    /// Balance across the replicas at `endpoints` from now on. Replicas which
+    /// are kept retain their connection and health, and calls in flight on
+    /// removed replicas carry on until they finish. No endpoints at all is
+    /// taken as a failed discovery, which keeps the current replicas.
+    pub(crate) fn update(&self, endpoints: &[Endpoint]) -> Result<(), Error> {
+        if endpoints.is_empty() {
+            return Err(Error::InvalidEndpoints("no replicas found".to_string()));
+        }
+        let current = self.replicas.load();
+        let mut replicas = Vec::new();
+        for endpoint in endpoints {
+            match current
+                .iter()
+                .find(|replica| replica.uri == *endpoint.uri())
+            {
+                Some(replica) => replicas.push(replica.clone()),
+                None => {
+                    log::info!("Adding replica {}", endpoint.uri());
+                    let replica = Replica::new(endpoint.uri().clone(), connect_lazy(endpoint)?);
+                    if let Some(interval) = *self.health_check_interval.lock().unwrap() {
+                        tokio::spawn(health_check(Arc::downgrade(&replica), interval));
+                    }
+                    replicas.push(replica);
+                }
+            }
+        }
+        for replica in current.iter() {
+            if !endpoints
+                .iter()
+                .any(|endpoint| *endpoint.uri() == replica.uri)
+            {
+                log::info!("Removing replica {}", replica.uri);
+            }
+        }
+        self.replicas.store(Arc::new(replicas));
+        Ok(())
+    }
+}
+
+async fn health_check(replica: Weak<Replica>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let replica = match replica.upgrade() {
+            Some(replica) => replica,
+            None => return,
+        };
+        let healthy = tokio::time::timeout(interval, replica.check())
+            .await
+            .unwrap_or(false);
+        replica.set_healthy(healthy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::health_server::{Health, HealthServer};
+    use proto::HealthCheckResponse;
+    use tokio_stream::wrappers::IntervalStream;
+    use tokio_stream::{Stream, StreamExt};
+    use tonic::{Request, Response, Status};
+
+    fn balancer(uris: &[&'static str], load_balancing: LoadBalancing) -> Balancer {
+        let replicas = uris
+            .iter()
+            .map(|uri| {
+                let endpoint = Endpoint::from_static(uri);
+                (endpoint.uri().clone(), connect_lazy(&endpoint).unwrap())
+            })
+            .collect();
+        Balancer::new(replicas, load_balancing)
+    }
+
+    fn uri_of(pick: &Pick) -> String {
+        pick.0.uri.to_string()
+    }
+
+    #[tokio::test]
+    async fn should_pick_healthy_replicas_in_turn() {
+        let balancer = balancer(
+            &["http://a:1", "http://b:1", "http://c:1"],
+            LoadBalancing::RoundRobin,
+        );
+        let picks: Vec<_> = (0..4).map(|_| uri_of(&balancer.pick().unwrap())).collect();
+        assert_eq!(
+            picks,
+            vec!["http://a:1/", "http://b:1/", "http://c:1/", "http://a:1/"]
+        );
+
+        balancer.replicas.load()[1].set_healthy(false);
+        for _ in 0..4 {
+            assert_ne!(uri_of(&balancer.pick().unwrap()), "http://b:1/");
+        }
+
+        for replica in balancer.replicas.load().iter() {
+            replica.set_healthy(false);
+        }
+        assert!(matches!(balancer.pick(), Err(Error::NoHealthyReplicas)));
+    }
+
+    #[tokio::test]
+    async fn should_pick_replica_with_fewer_calls_in_flight() {
+        let balancer = balancer(
+            &["http://a:1", "http://b:1"],
+            LoadBalancing::PowerOfTwoChoices,
+        );
+        let busy = balancer.pick().unwrap();
+        for _ in 0..4 {
+            assert_ne!(uri_of(&balancer.pick().unwrap()), uri_of(&busy));
+        }
+
+        // NOTE: dropped picks are no longer in flight
+        let idle = balancer.pick().unwrap();
+        drop(busy);
+        assert_ne!(uri_of(&balancer.pick().unwrap()), uri_of(&idle));
+    }
+
+    #[tokio::test]
+    async fn should_hold_pick_until_body_ends() {
+        let balancer = balancer(&["http://a:1"], LoadBalancing::PowerOfTwoChoices);
+        let in_flight = || {
+            balancer.replicas.load()[0]
+                .in_flight
+                .load(Ordering::Relaxed)
+        };
+
+        let mut body = balancer
+            .pick()
+            .unwrap()
+            .hold_until_end(Body::from("message"));
+        assert_eq!(in_flight(), 1);
+        assert!(body.next().await.is_some());
+        assert_eq!(in_flight(), 1);
+        assert!(body.next().await.is_none());
+        assert_eq!(in_flight(), 0);
+
+        drop(balancer.pick().unwrap().hold_until_end(Body::empty()));
+        assert_eq!(in_flight(), 0);
+    }
+
+    #[derive(Clone)]
+    struct FakeHealth(Arc<Mutex<ServingStatus>>);
+
+    #[tonic::async_trait]
+    impl Health for FakeHealth {
+        async fn check(
+            &self,
+            _request: Request<HealthCheckRequest>,
+        ) -> Result<Response<HealthCheckResponse>, Status> {
+            Ok(Response::new(HealthCheckResponse {
+                status: *self.0.lock().unwrap() as i32,
+            }))
+        }
+
+        type WatchStream =
+            Pin<Box<dyn Stream<Item = Result<HealthCheckResponse, Status>> + Send + Sync>>;
+
+        /// Current status every 10ms.
+        async fn watch(
+            &self,
+            _request: Request<HealthCheckRequest>,
+        ) -> Result<Response<Self::WatchStream>, Status> {
+            let status = self.0.clone();
+            let interval = tokio::time::interval(Duration::from_millis(10));
+            Ok(Response::new(Box::pin(IntervalStream::new(interval).map(
+                move |_| {
+                    Ok(HealthCheckResponse {
+                        status: *status.lock().unwrap() as i32,
+                    })
+                },
+            ))))
+        }
+    }
+
+    async fn serve_health(health: FakeHealth) -> Endpoint {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(HealthServer::new(health))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+        Endpoint::from_shared(format!("http://{}", addr)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_check_health() {
+        let health = FakeHealth(Arc::new(Mutex::new(ServingStatus::Serving)));
+        let endpoint = serve_health(health.clone()).await;
+        let balancer = Balancer::new(
+            vec![(endpoint.uri().clone(), connect_lazy(&endpoint).unwrap())],
+            LoadBalancing::RoundRobin,
+        );
+        let tasks = balancer.health_check_every(Duration::from_millis(10));
+
+        *health.0.lock().unwrap() = ServingStatus::NotServing;
+        while balancer.pick().is_ok() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        *health.0.lock().unwrap() = ServingStatus::Serving;
+        while balancer.pick().is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // NOTE: the checks stop once the balancer is dropped
+        drop(balancer);
+        for task in tasks {
+            tokio::time::timeout(Duration::from_secs(1), task)
+                .await
+                .expect("health check did not stop")
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn should_keep_calls_to_removed_replicas() -> Result<(), Status> {
+        let endpoint = serve_health(FakeHealth(Arc::new(Mutex::new(ServingStatus::Serving)))).await;
+        let other = Endpoint::from_static("http://a:1");
+        let balancer = Balancer::new(Vec::new(), LoadBalancing::RoundRobin);
+        balancer.update(&[endpoint.clone(), other.clone()]).unwrap();
+        balancer.replicas.load()[1].set_healthy(false);
+
+        let pick = balancer.pick().unwrap();
+        assert_eq!(pick.0.uri, *endpoint.uri());
+        let mut watch = HealthClient::new(pick.0.channel.clone())
+            .watch(HealthCheckRequest {
+                service: String::new(),
+            })
+            .await?
+            .into_inner();
+        assert!(watch.message().await?.is_some());
+
+        balancer.update(&[other]).unwrap();
+        drop(pick);
+        assert!(watch.message().await?.is_some());
+
+        // NOTE: kept replicas are still unhealthy
+        assert!(matches!(balancer.pick(), Err(Error::NoHealthyReplicas)));
+
+        assert!(matches!(
+            balancer.update(&[]),
+            Err(Error::InvalidEndpoints(_))
+        ));
+        assert_eq!(balancer.replicas.load().len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+use crate::Error;
+use futures::future::BoxFuture;
+use hyper::http::{Request, Response, Uri};
+use hyper::Body;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::transport::{Channel, Endpoint, Error as TransportError};
+use tower::Service;
+
+const UNIX_SCHEME: &str = "unix";
+
+/// Characters escaped in the socket path of a unix address.
+const PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Address of a replica listening on the unix domain socket at the absolute
+/// `path`, such as `unix://localhost/run/greeter.sock`. Connections to it
+/// are never encrypted.
+pub fn unix_socket_uri(path: &str) -> Result<Uri, Error> {
+    if !path.starts_with('/') {
+        return Err(Error::InvalidEndpoints(format!(
+            "unix socket path is not absolute: {}",
+            path
+        )));
+    }
+    format!(
+        "{}://localhost{}",
+        UNIX_SCHEME,
+        utf8_percent_encode(path, PATH)
+    )
+    .parse()
+    .map_err(|_| Error::InvalidEndpoints(path.to_string()))
+}
+
+pub(crate) fn unix_socket_path(uri: &Uri) -> Option<String> {
+    if uri.scheme_str() != Some(UNIX_SCHEME) {
+        return None;
+    }
+    Some(
+        percent_decode_str(uri.path())
+            .decode_utf8_lossy()
+            .into_owned(),
+    )
+}
+
+#[cfg(not(unix))]
+fn unsupported(path: String) -> Error {
+    Error::InvalidEndpoints(format!("unix sockets are not supported: {}", path))
+}
+
+/// Connect to the replica at `endpoint`, failing if it cannot be reached.
+pub(crate) async fn connect(endpoint: &Endpoint) -> Result<UpstreamChannel, Error> {
+    match unix_socket_path(endpoint.uri()) {
+        #[cfg(unix)]
+        Some(path) => {
+            let channel = unix::connect(endpoint.clone(), path.clone()).await?;
+            Ok(UpstreamChannel::Unix(unix::UnixChannel::new(
+                endpoint.clone(),
+                path,
+                Some(channel),
+            )))
+        }
+        #[cfg(not(unix))]
+        Some(path) => Err(unsupported(path)),
+        None => Ok(UpstreamChannel::Tcp(endpoint.connect().await?)),
+    }
+}
+
+/// Channel to the replica at `endpoint`, which connects on first use.
+pub(crate) fn connect_lazy(endpoint: &Endpoint) -> Result<UpstreamChannel, Error> {
+    match unix_socket_path(endpoint.uri()) {
+        #[cfg(unix)]
+        Some(path) => Ok(UpstreamChannel::Unix(unix::UnixChannel::new(
+            endpoint.clone(),
+            path,
+            None,
+        ))),
+        #[cfg(not(unix))]
+        Some(path) => Err(unsupported(path)),
+        None => Ok(UpstreamChannel::Tcp(endpoint.connect_lazy()?)),
+    }
+}
+
+/// Channel to a replica, over TCP or a unix domain socket.
+#[derive(Clone)]
+pub(crate) enum UpstreamChannel {
+    Tcp(Channel),
+    #[cfg(unix)]
+    Unix(unix::UnixChannel),
+}
+
+impl Service<Request<BoxBody>> for UpstreamChannel {
+    type Response = Response<Body>;
+    type Error = TransportError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self {
+            UpstreamChannel::Tcp(channel) => channel.poll_ready(cx),
+            #[cfg(unix)]
+            UpstreamChannel::Unix(channel) => channel.poll_ready(cx),
+        }
+    }
+
+    fn call(&mut self, request: Request<BoxBody>) -> Self::Future {
+        match self {
+            UpstreamChannel::Tcp(channel) => Box::pin(channel.call(request)),
+            #[cfg(unix)]
+            UpstreamChannel::Unix(channel) => channel.call(request),
+        }
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use std::future::Future;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::UnixStream;
+
+    /// Connect to the socket at `path` with the settings of `endpoint`.
+    pub(crate) async fn connect(
+        endpoint: Endpoint,
+        path: String,
+    ) -> Result<Channel, TransportError> {
+        // NOTE: the origin only names the upstream in requests, every
+        // connection is made to the socket
+        endpoint
+            .connect_with_connector(tower::service_fn(move |_: Uri| {
+                UnixStream::connect(path.clone())
+            }))
+            .await
+    }
+
+    /// Connect unless a clone already has, waiting for a connection which
+    /// is in progress rather than opening another.
+    async fn connect_shared(
+        endpoint: Arc<Endpoint>,
+        path: String,
+        connected: Arc<tokio::sync::Mutex<Option<Channel>>>,
+    ) -> Result<Channel, TransportError> {
+        let mut connected = connected.lock().await;
+        if let Some(channel) = &*connected {
+            return Ok(channel.clone());
+        }
+        let channel = connect(endpoint.as_ref().clone(), path).await?;
+        *connected = Some(channel.clone());
+        Ok(channel)
+    }
+
+    /// Channel to a unix domain socket, connected when first polled since
+    /// tonic only connects lazily over TCP. Clones share the connection.
+    pub(crate) struct UnixChannel {
+        endpoint: Arc<Endpoint>,
+        path: String,
+        /// Locked while connecting, so that clones wait for the connection.
+        connected: Arc<tokio::sync::Mutex<Option<Channel>>>,
+        channel: Option<Channel>,
+        /// Only ever accessed mutably, the mutex keeps the channel `Sync`.
+        connecting: Mutex<Option<BoxFuture<'static, Result<Channel, TransportError>>>>,
+    }
+
+    impl UnixChannel {
+        pub(crate) fn new(endpoint: Endpoint, path: String, channel: Option<Channel>) -> Self {
+            Self {
+                endpoint: Arc::new(endpoint),
+                path,
+                connected: Arc::new(tokio::sync::Mutex::new(channel.clone())),
+                channel,
+                connecting: Mutex::new(None),
+            }
+        }
+
+        pub(crate) fn poll_ready(
+            &mut self,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), TransportError>> {
+            loop {
+                if let Some(channel) = &mut self.channel {
+                    return channel.poll_ready(cx);
+                }
+                // NOTE: locked while a clone is connecting, which is waited for below
+                if let Some(channel) = self
+                    .connected
+                    .try_lock()
+                    .ok()
+                    .and_then(|connected| connected.clone())
+                {
+                    self.channel = Some(channel);
+                    continue;
+                }
+
+                let (endpoint, path) = (self.endpoint.clone(), self.path.clone());
+                let connected = self.connected.clone();
+                let connecting = self.connecting.get_mut().unwrap();
+                let future = connecting
+                    .get_or_insert_with(|| Box::pin(connect_shared(endpoint, path, connected)));
+                let channel = match Future::poll(future.as_mut(), cx) {
+                    Poll::Ready(channel) => channel,
+                    Poll::Pending => return Poll::Pending,
+                };
+                // NOTE: failed connections are attempted again when next polled
+                *connecting = None;
+                self.channel = Some(channel?);
+            }
+        }
+
+        pub(crate) fn call(
+            &mut self,
+            request: Request<BoxBody>,
+        ) -> BoxFuture<'static, Result<Response<Body>, TransportError>> {
+            let channel = self
+                .channel
+                .as_mut()
+                .expect("poll_ready must be called first");
+            Box::pin(channel.call(request))
+        }
+    }
+
+    impl Clone for UnixChannel {
+        fn clone(&self) -> Self {
+            Self {
+                endpoint: self.endpoint.clone(),
+                path: self.path.clone(),
+                connected: self.connected.clone(),
+                channel: self.channel.clone(),
+                connecting: Mutex::new(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    #[cfg(unix)]
+    use std::sync::Arc;
+    #[cfg(unix)]
+    use std::time::Duration;
+
+    /// HTTP/2 server on a unix socket which never answers, counting the
+    /// connections it accepts.
+    #[cfg(unix)]
+    fn serve_unix(path: &std::path::Path) -> Arc<AtomicUsize> {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(
+                    hyper::server::conn::Http::new()
+                        .http2_only(true)
+                        .serve_connection(
+                            stream,
+                            tower::service_fn(|_: Request<Body>| {
+                                futures::future::pending::<Result<Response<Body>, Error>>()
+                            }),
+                        ),
+                );
+            }
+        });
+        connections
+    }
+
+    #[test]
+    fn should_address_unix_sockets() -> Result<(), Error> {
+        let uri = unix_socket_uri("/run/grpc server.sock")?;
+        assert_eq!(uri, "unix://localhost/run/grpc%20server.sock");
+        assert_eq!(
+            unix_socket_path(&uri).as_deref(),
+            Some("/run/grpc server.sock")
+        );
+        assert_eq!(
+            unix_socket_path(&Uri::from_static("http://[::1]:50052")),
+            None
+        );
+        assert!(unix_socket_uri("grpc.sock").is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn should_share_unix_connections() -> Result<(), Error> {
+        let path = crate::test_util::temp_path("shared.sock");
+        let connections = serve_unix(&path);
+
+        let endpoint = Endpoint::from(unix_socket_uri(path.to_str().unwrap())?);
+        let mut first = connect_lazy(&endpoint)?;
+        let (mut second, mut third) = (first.clone(), first.clone());
+        let (first, second, third) = tokio::join!(
+            futures::future::poll_fn(|cx| first.poll_ready(cx)),
+            futures::future::poll_fn(|cx| second.poll_ready(cx)),
+            futures::future::poll_fn(|cx| third.poll_ready(cx)),
+        );
+        first?;
+        second?;
+        third?;
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn should_keep_unix_endpoint_settings() -> Result<(), Error> {
+        let path = crate::test_util::temp_path("timeout.sock");
+        serve_unix(&path);
+
+        let endpoint = Endpoint::from(unix_socket_uri(path.to_str().unwrap())?)
+            .timeout(Duration::from_millis(10));
+        let mut channel = connect_lazy(&endpoint)?;
+        futures::future::poll_fn(|cx| channel.poll_ready(cx)).await?;
+        let request = Request::post("/helloworld.Greeter/SayHello")
+            .body(BoxBody::empty())
+            .unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), channel.call(request))
+            .await
+            .expect("endpoint timeout was not applied");
+        assert!(result.is_err());
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+use crate::request::{split_frame, TRAILER_FLAG};
+use crate::{Error, Mode, FRAME_HEADER_SIZE, GRPC_CONTENT_TYPE};
+use bytes::Bytes;
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::http::{
+    header::{ACCEPT, CONTENT_TYPE, TE},
+    HeaderMap, HeaderName, HeaderValue, Uri, Version,
+};
+use hyper::{Body, Client, Request as HttpRequest, Response as HttpResponse};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::Status;
+use tower::Service;
+
+const X_GRPC_WEB_HEADER: &str = "x-grpc-web";
+
+/// Transport for tonic clients which speaks gRPC-Web over HTTP/1.1, the
+/// way a browser would, e.g.
+/// `GreeterClient::new(GrpcWebClientService::new(uri, Mode::Text))`.
+///
+/// NOTE: client-side and bi-directional streaming are not supported by
+/// the protocol, and the request message is buffered before it is sent.
+#[derive(Debug, Clone)]
+pub struct GrpcWebClientService {
+    client: Client<HttpConnector>,
+    origin: Uri,
+    mode: Mode,
+}
+
+impl GrpcWebClientService {
+    /// Send requests to the gRPC-Web server at `origin`, e.g.
+    /// `http://[::1]:8080`.
+    pub fn new(origin: Uri, mode: Mode) -> Self {
+        Self {
+            client: Client::new(),
+            origin,
+            mode,
+        }
+    }
+}
+
+impl Service<HttpRequest<BoxBody>> for GrpcWebClientService {
+    type Response = HttpResponse<GrpcWebClientBody>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, grpc_request: HttpRequest<BoxBody>) -> Self::Future {
+        let client = self.client.clone();
+        let origin = self.origin.clone();
+        let mode = self.mode;
+
+        Box::pin(async move {
+            let http_request = into_grpc_web_request(grpc_request, origin, mode).await?;
+            let http_response = client.request(http_request).await?;
+            Ok(into_grpc_response(http_response, mode))
+        })
+    }
+}
+
+async fn into_grpc_web_request(
+    grpc_request: HttpRequest<BoxBody>,
+    origin: Uri,
+    mode: Mode,
+) -> Result<HttpRequest<Body>, Error> {
+    let (mut parts, body) = grpc_request.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+
+    // NOTE: tonic only sets the path, the channel would add the origin
+    let mut uri = origin.into_parts();
+    uri.path_and_query = parts.uri.path_and_query().cloned();
+    parts.uri = Uri::from_parts(uri).map_err(|_| Error::InvalidRequest)?;
+    parts.version = Version::HTTP_11;
+
+    let headers = &mut parts.headers;
+    headers.remove(TE);
+    headers.insert(CONTENT_TYPE, mode.content_type());
+    headers.insert(ACCEPT, mode.content_type());
+    headers.insert(X_GRPC_WEB_HEADER, HeaderValue::from_static("1"));
+
+    Ok(HttpRequest::from_parts(
+        parts,
+        mode.encode(body.to_vec()).into(),
+    ))
+}
+
+fn into_grpc_response(
+    http_response: HttpResponse<Body>,
+    mode: Mode,
+) -> HttpResponse<GrpcWebClientBody> {
+    let (mut parts, body) = http_response.into_parts();
+    let mode = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(Mode::from_content_type)
+        .unwrap_or(mode);
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static(GRPC_CONTENT_TYPE));
+    HttpResponse::from_parts(parts, GrpcWebClientBody::new(body, mode))
+}
+
+/// Body of a gRPC-Web response, decoded into gRPC frames followed by the
+/// trailers sent in its trailer frame.
+#[derive(Debug)]
+pub struct GrpcWebClientBody {
+    inner: Body,
+    mode: Mode,
+    /// Base64 which does not yet make up a whole quantum.
+    encoded: Vec<u8>,
+    /// Decoded bytes which do not yet make up a whole frame.
+    decoded: Vec<u8>,
+    trailers: Option<HeaderMap>,
+    done: bool,
+}
+
+impl GrpcWebClientBody {
+    fn new(inner: Body, mode: Mode) -> Self {
+        Self {
+            inner,
+            mode,
+            encoded: Vec::new(),
+            decoded: Vec::new(),
+            trailers: None,
+            done: false,
+        }
+    }
+
+    fn push(&mut self, data: Bytes) -> Result<(), Error> {
+        match self.mode {
+            Mode::Binary => self.decoded.extend_from_slice(&data),
+            Mode::Text => {
+                self.encoded.extend_from_slice(&data);
+                let decoded = decode_text(&mut self.encoded)?;
+                self.decoded.extend(decoded);
+            }
+        }
+        Ok(())
+    }
+
+    /// The next data frame, or `None` once the trailer frame is read.
+    fn next_frame(&mut self) -> Result<Option<Bytes>, Error> {
+        match split_frame(&mut self.decoded) {
+            Some(frame) if frame[0] & TRAILER_FLAG != 0 => {
+                self.trailers = Some(decode_trailers(&frame[FRAME_HEADER_SIZE..])?);
+                self.done = true;
+                Ok(None)
+            }
+            frame => Ok(frame.map(Bytes::from)),
+        }
+    }
+}
+
+impl HttpBody for GrpcWebClientBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            if this.done {
+                return Poll::Ready(None);
+            }
+            match this.next_frame() {
+                Ok(Some(frame)) => return Poll::Ready(Some(Ok(frame))),
+                Ok(None) if this.done => return Poll::Ready(None),
+                Ok(None) => (),
+                Err(err) => return Poll::Ready(Some(Err(err.into()))),
+            }
+
+            match futures::ready!(Pin::new(&mut this.inner).poll_data(cx)) {
+                Some(Ok(data)) => {
+                    if let Err(err) = this.push(data) {
+                        return Poll::Ready(Some(Err(err.into())));
+                    }
+                }
+                Some(Err(err)) => return Poll::Ready(Some(Err(Error::from(err).into()))),
+                None => {
+                    this.done = true;
+                    if !this.encoded.is_empty() || !this.decoded.is_empty() {
+                        return Poll::Ready(Some(Err(Error::TruncatedFrame.into())));
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(self.get_mut().trailers.take()))
+    }
+}
+
+/// Decode every complete quantum of `encoded`. Messages may be encoded
+/// separately, so padding can appear in the middle of a body.
+fn decode_text(encoded: &mut Vec<u8>) -> Result<Vec<u8>, Error> {
+    let mut decoded = Vec::new();
+    let (mut start, mut end) = (0, 0);
+    for quantum in encoded.chunks_exact(4) {
+        end += 4;
+        if quantum.contains(&b'=') {
+            decoded.extend(base64::decode(&encoded[start..end])?);
+            start = end;
+        }
+    }
+    decoded.extend(base64::decode(&encoded[start..end])?);
+    encoded.drain(..end);
+    Ok(decoded)
+}
+
+/// Parse the `name:value\r\n` lines of a trailer frame.
+fn decode_trailers(data: &[u8]) -> Result<HeaderMap, Error> {
+    let mut trailers = HeaderMap::new();
+    for line in data.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        let split = line
+            .iter()
+            .position(|b| *b == b':')
+            .ok_or(Error::InvalidTrailers)?;
+        let name = HeaderName::from_bytes(&line[..split].to_ascii_lowercase())
+            .map_err(|_| Error::InvalidTrailers)?;
+        let value = std::str::from_utf8(&line[split + 1..]).map_err(|_| Error::InvalidTrailers)?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|_| Error::InvalidTrailers)?;
+        trailers.append(name, value);
+    }
+    Ok(trailers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GrpcWebLayer, ProxyCodec};
+    use futures::stream::StreamExt;
+    use hyper::service::make_service_fn;
+    use std::convert::Infallible;
+    use tonic::client::Grpc as GrpcClient;
+    use tonic::codegen::http::uri::PathAndQuery;
+    use tonic::{Code, Request};
+    use tower::Layer;
+
+    /// Answers `/test.Echo/<n>` with the request message `n` times.
+    async fn echo(grpc_request: HttpRequest<Body>) -> Result<HttpResponse<Body>, Infallible> {
+        let count: usize = grpc_request.uri().path()[11..].parse().unwrap();
+        if count == 0 {
+            let mut http_response = HttpResponse::new(Body::empty());
+            http_response
+                .headers_mut()
+                .insert("grpc-status", HeaderValue::from_static("5"));
+            return Ok(http_response);
+        }
+
+        let message = hyper::body::to_bytes(grpc_request.into_body())
+            .await
+            .unwrap();
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..count {
+                sender.send_data(message.clone()).await?;
+            }
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            trailers.insert("x-trailer", HeaderValue::from_static("a"));
+            sender.send_trailers(trailers).await
+        });
+        Ok(HttpResponse::new(body))
+    }
+
+    async fn serve() -> Uri {
+        let service = GrpcWebLayer::new().layer(tower::service_fn(echo));
+        let make_svc = make_service_fn(move |_| {
+            let service = service.clone();
+            async move { Ok::<_, Infallible>(service) }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let uri = format!("http://{}", server.local_addr()).parse().unwrap();
+        tokio::spawn(server);
+        uri
+    }
+
+    #[tokio::test]
+    async fn should_call_grpc_web_server() -> Result<(), Error> {
+        let origin = serve().await;
+        for mode in vec![Mode::Binary, Mode::Text] {
+            let mut client = GrpcClient::new(GrpcWebClientService::new(origin.clone(), mode));
+
+            client.ready().await?;
+            let grpc_response = client
+                .unary(
+                    Request::new(vec![10, 1, 97]),
+                    PathAndQuery::from_static("/test.Echo/1"),
+                    ProxyCodec::default(),
+                )
+                .await?;
+            assert_eq!(grpc_response.metadata().get("x-trailer").unwrap(), "a");
+            assert_eq!(grpc_response.into_inner(), vec![10, 1, 97]);
+
+            client.ready().await?;
+            let mut streaming = client
+                .server_streaming(
+                    Request::new(vec![10, 1, 98]),
+                    PathAndQuery::from_static("/test.Echo/3"),
+                    ProxyCodec::default(),
+                )
+                .await?
+                .into_inner();
+            let mut messages = Vec::new();
+            while let Some(message) = streaming.next().await {
+                messages.push(message?);
+            }
+            assert_eq!(messages, vec![vec![10, 1, 98]; 3]);
+            assert_eq!(
+                streaming
+                    .trailers()
+                    .await?
+                    .unwrap()
+                    .get("x-trailer")
+                    .unwrap(),
+                "a"
+            );
+
+            client.ready().await?;
+            let status = client
+                .unary(
+                    Request::new(vec![]),
+                    PathAndQuery::from_static("/test.Echo/0"),
+                    ProxyCodec::default(),
+                )
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::NotFound);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_decode_text() -> Result<(), Error> {
+        let mut encoded = b"AAAAAAcKBVRvbmlj".to_vec();
+        assert_eq!(
+            decode_text(&mut encoded)?,
+            vec![0, 0, 0, 0, 7, 10, 5, 84, 111, 110, 105, 99]
+        );
+        assert!(encoded.is_empty());
+
+        let mut encoded = b"AAAAAAEHgAAAAA9ncnBjLXN0YXR1czowDQo=gA".to_vec();
+        assert_eq!(decode_text(&mut encoded)?.len(), 26);
+        assert_eq!(encoded, b"gA".to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_decode_trailers() -> Result<(), Error> {
+        let trailers = decode_trailers(b"grpc-status:0\r\nGrpc-Message: ok\r\nx-a:1\r\nx-a:2\r\n")?;
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers["grpc-message"], "ok");
+        assert_eq!(trailers.get_all("x-a").iter().count(), 2);
+
+        assert!(matches!(
+            decode_trailers(b"grpc-status"),
+            Err(Error::InvalidTrailers)
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+use crate::request::{split_frame, COMPRESSED_FLAG};
+use crate::response::encode_message;
+use crate::{Error, FRAME_HEADER_SIZE};
+use bytes::Bytes;
+use flate2::read::{GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder};
+use futures::future::BoxFuture;
+use hyper::body::HttpBody;
+use hyper::http::HeaderMap;
+use hyper::{Body, Request as HttpRequest, Response as HttpResponse};
+use std::io::Read;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::Status;
+use tower::Service;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+pub const GRPC_ENCODING_HEADER: &str = "grpc-encoding";
+pub const GRPC_ACCEPT_ENCODING_HEADER: &str = "grpc-accept-encoding";
+
+/// Message encodings understood by this proxy, as advertised in
+/// `grpc-accept-encoding`.
+pub const SUPPORTED_ENCODINGS: &str = "identity,gzip,deflate,zstd";
+
+/// Largest message accepted once decompressed, unless configured
+/// otherwise. The same as the default receive limit of gRPC servers.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Compression applied to individual messages, see
+/// https://github.com/grpc/grpc/blob/master/doc/compression.md
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Identity,
+    Gzip,
+    /// The zlib format, as used by gRPC for `deflate`.
+    Deflate,
+    Zstd,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            "identity" => Some(Encoding::Identity),
+            "gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// Encoding of the messages sent by the client, from `grpc-encoding`.
+    pub fn from_encoding_header(headers: &HeaderMap) -> Result<Self, Error> {
+        match headers.get(GRPC_ENCODING_HEADER) {
+            Some(value) => {
+                let name = value.to_str().unwrap_or_default();
+                Self::from_name(name).ok_or_else(|| Error::UnsupportedEncoding(name.to_string()))
+            }
+            None => Ok(Encoding::Identity),
+        }
+    }
+
+    /// First encoding listed in `grpc-accept-encoding` that is supported,
+    /// in the client's order of preference.
+    pub fn from_accept_encoding_header(headers: &HeaderMap) -> Self {
+        headers
+            .get_all(GRPC_ACCEPT_ENCODING_HEADER)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(Self::from_name)
+            .next()
+            .unwrap_or_default()
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        match self {
+            Encoding::Identity => out.extend_from_slice(data),
+            Encoding::Gzip => {
+                GzEncoder::new(data, flate2::Compression::default()).read_to_end(&mut out)?;
+            }
+            Encoding::Deflate => {
+                ZlibEncoder::new(data, flate2::Compression::default()).read_to_end(&mut out)?;
+            }
+            Encoding::Zstd => out = zstd::encode_all(data, 0)?,
+        }
+        Ok(out)
+    }
+
+    /// Decompress a message, failing with `RESOURCE_EXHAUSTED` once it
+    /// grows past `max_size` bytes rather than reading all of it.
+    pub fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, Error> {
+        // NOTE: one byte more than allowed is read to tell when it is exceeded
+        let limit = max_size as u64 + 1;
+        let mut out = Vec::new();
+        match self {
+            Encoding::Identity => out.extend_from_slice(data),
+            Encoding::Gzip => {
+                GzDecoder::new(data).take(limit).read_to_end(&mut out)?;
+            }
+            Encoding::Deflate => {
+                ZlibDecoder::new(data).take(limit).read_to_end(&mut out)?;
+            }
+            Encoding::Zstd => {
+                ZstdDecoder::new(data)?.take(limit).read_to_end(&mut out)?;
+            }
+        }
+        if out.len() > max_size {
+            return Err(Error::MessageTooLarge(max_size));
+        }
+        Ok(out)
+    }
+}
+
+/// Channel to an upstream which decompresses the messages it answers with,
+/// since tonic only reads uncompressed messages.
+#[derive(Clone)]
+pub(crate) struct Decompress<S> {
+    inner: S,
+    max_size: usize,
+}
+
+impl<S> Decompress<S> {
+    pub(crate) fn new(inner: S, max_size: usize) -> Self {
+        Self { inner, max_size }
+    }
+}
+
+impl<S> Service<HttpRequest<BoxBody>> for Decompress<S>
+where
+    S: Service<HttpRequest<BoxBody>, Response = HttpResponse<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = HttpResponse<DecompressBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: HttpRequest<BoxBody>) -> Self::Future {
+        let max_size = self.max_size;
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let (mut parts, body) = response.await?.into_parts();
+            // NOTE: compressed messages in an unknown encoding are passed on
+            // for tonic to reject
+            let encoding = Encoding::from_encoding_header(&parts.headers).unwrap_or_default();
+            parts.headers.remove(GRPC_ENCODING_HEADER);
+            let body = DecompressBody {
+                inner: body,
+                encoding,
+                max_size,
+                buf: Vec::new(),
+            };
+            Ok(HttpResponse::from_parts(parts, body))
+        })
+    }
+}
+
+/// Body of an upstream response, with its messages decompressed.
+pub(crate) struct DecompressBody {
+    inner: Body,
+    encoding: Encoding,
+    max_size: usize,
+    buf: Vec<u8>,
+}
+
+impl DecompressBody {
+    fn decompress_frame(&self, frame: Vec<u8>) -> Result<Bytes, Error> {
+        if frame[0] & COMPRESSED_FLAG == 0 || self.encoding == Encoding::Identity {
+            return Ok(frame.into());
+        }
+        let message = self
+            .encoding
+            .decompress(&frame[FRAME_HEADER_SIZE..], self.max_size)?;
+        Ok(encode_message(message, Encoding::Identity)?.into())
+    }
+}
+
+impl HttpBody for DecompressBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(frame) = split_frame(&mut this.buf) {
+                return Poll::Ready(Some(this.decompress_frame(frame).map_err(Status::from)));
+            }
+
+            match futures::ready!(Pin::new(&mut this.inner).poll_data(cx)) {
+                Some(Ok(data)) => this.buf.extend_from_slice(&data),
+                Some(Err(err)) => return Poll::Ready(Some(Err(Error::from(err).into()))),
+                // NOTE: tonic reports any truncated frame left over
+                None if this.buf.is_empty() => return Poll::Ready(None),
+                None => return Poll::Ready(Some(Ok(std::mem::take(&mut this.buf).into()))),
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_trailers(cx)
+            .map_err(|err| Error::from(err).into())
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.buf.is_empty() && self.inner.is_end_stream()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::http::HeaderValue;
+
+    #[test]
+    fn should_round_trip_messages() -> Result<(), Error> {
+        let message = b"hello hello hello hello hello".to_vec();
+        for encoding in &[
+            Encoding::Identity,
+            Encoding::Gzip,
+            Encoding::Deflate,
+            Encoding::Zstd,
+        ] {
+            let compressed = encoding.compress(&message)?;
+            assert_eq!(
+                encoding.decompress(&compressed, DEFAULT_MAX_MESSAGE_SIZE)?,
+                message
+            );
+        }
+        assert!(Encoding::Gzip
+            .decompress(b"not gzip", DEFAULT_MAX_MESSAGE_SIZE)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_limit_decompressed_size() -> Result<(), Error> {
+        // NOTE: a few hundred bytes which expand to a megabyte
+        let bomb = vec![0; 1024 * 1024];
+        for encoding in &[Encoding::Gzip, Encoding::Deflate, Encoding::Zstd] {
+            let compressed = encoding.compress(&bomb)?;
+            assert!(matches!(
+                encoding.decompress(&compressed, 1024),
+                Err(Error::MessageTooLarge(1024))
+            ));
+            assert_eq!(encoding.decompress(&compressed, bomb.len())?, bomb);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_negotiate_encoding() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            Encoding::from_encoding_header(&headers).unwrap(),
+            Encoding::Identity
+        );
+        assert_eq!(
+            Encoding::from_accept_encoding_header(&headers),
+            Encoding::Identity
+        );
+
+        headers.insert(GRPC_ENCODING_HEADER, HeaderValue::from_static("gzip"));
+        headers.insert(
+            GRPC_ACCEPT_ENCODING_HEADER,
+            HeaderValue::from_static("br, zstd, gzip"),
+        );
+        assert_eq!(
+            Encoding::from_encoding_header(&headers).unwrap(),
+            Encoding::Gzip
+        );
+        assert_eq!(
+            Encoding::from_accept_encoding_header(&headers),
+            Encoding::Zstd
+        );
+
+        headers.insert(GRPC_ENCODING_HEADER, HeaderValue::from_static("br"));
+        assert!(matches!(
+            Encoding::from_encoding_header(&headers),
+            Err(Error::UnsupportedEncoding(name)) if name == "br"
+        ));
+    }
+}
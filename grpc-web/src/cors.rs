@@ -0,0 +1,348 @@
+use crate::route::wildcard_match;
+use hyper::http::{
+    header::{
+        ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+        ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+        ORIGIN, VARY,
+    },
+    HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+};
+use hyper::{Body, Request as HttpRequest, Response as HttpResponse};
+use std::time::Duration;
+
+const ALLOWED_METHODS: &str = "POST, OPTIONS";
+
+/// Pattern allowing every origin.
+const ANY_ORIGIN: &str = "*";
+
+/// Response headers a gRPC-Web client needs to read, exposed in addition
+/// to any configured ones.
+const EXPOSED_HEADERS: &[&str] = &[
+    "grpc-status",
+    "grpc-message",
+    "grpc-status-details-bin",
+    "grpc-encoding",
+    "grpc-accept-encoding",
+];
+
+/// Which browser origins may call the proxy, answering preflight requests
+/// and adding the CORS headers to responses.
+#[derive(Debug, Clone)]
+pub struct Cors {
+    allowed_origins: Vec<String>,
+    allowed_headers: Vec<String>,
+    exposed_headers: HeaderValue,
+    allow_credentials: bool,
+    max_age: Option<Duration>,
+}
+
+/// Allows any origin to send any header, without credentials.
+impl Default for Cors {
+    fn default() -> Self {
+        Self::new(
+            vec!["*".to_string()],
+            vec!["*".to_string()],
+            vec![],
+            false,
+            None,
+        )
+    }
+}
+
+impl Cors {
+    /// Origins are exact, `*` for any origin, or contain a single `*`
+    /// wildcard (e.g. `https://*.example.com`). Allowed headers may be
+    /// `*`, in which case the requested headers are allowed. Credentials
+    /// are never allowed for origins only matched by a bare `*`.
+    pub fn new(
+        allowed_origins: Vec<String>,
+        allowed_headers: Vec<String>,
+        exposed_headers: Vec<String>,
+        allow_credentials: bool,
+        max_age: Option<Duration>,
+    ) -> Self {
+        let exposed_headers = EXPOSED_HEADERS
+            .iter()
+            .map(|name| name.to_string())
+            .chain(exposed_headers.into_iter().filter(|name| {
+                let valid = HeaderName::from_bytes(name.as_bytes()).is_ok();
+                if !valid {
+                    log::warn!("Not exposing invalid header: {}", name);
+                }
+                valid
+            }))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if allow_credentials && allowed_origins.iter().any(|pattern| pattern == ANY_ORIGIN) {
+            log::warn!(
+                "Not allowing credentials for any origin, list the origins to allow instead"
+            );
+        }
+        Self {
+            allowed_origins,
+            allowed_headers,
+            exposed_headers: HeaderValue::from_str(&exposed_headers)
+                .expect("Exposed header names are valid"),
+            allow_credentials,
+            max_age,
+        }
+    }
+
+    pub fn is_allowed(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|pattern| wildcard_match(pattern, origin))
+    }
+
+    /// The request origin if it is listed, or `*` if it is only allowed by
+    /// a bare `*`, which browsers never send credentials to.
+    fn allowed_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        let origin = origin?;
+        let value = origin.to_str().ok()?;
+        let mut patterns = self.allowed_origins.iter();
+        if patterns
+            .clone()
+            .any(|pattern| pattern != ANY_ORIGIN && wildcard_match(pattern, value))
+        {
+            Some(origin.clone())
+        } else if patterns.any(|pattern| pattern == ANY_ORIGIN) {
+            Some(HeaderValue::from_static(ANY_ORIGIN))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_preflight<B>(http_request: &HttpRequest<B>) -> bool {
+        http_request.method() == Method::OPTIONS
+            && http_request.headers().contains_key(ORIGIN)
+            && http_request
+                .headers()
+                .contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    /// Answer a preflight request, or forbid it if the origin or method
+    /// are not allowed.
+    pub fn preflight_response<B>(&self, http_request: &HttpRequest<B>) -> HttpResponse<Body> {
+        let mut http_response = HttpResponse::new(Body::empty());
+        let request_headers = http_request.headers();
+        let origin = match self.allowed_origin(request_headers.get(ORIGIN)) {
+            Some(origin)
+                if matches!(
+                    request_headers.get(ACCESS_CONTROL_REQUEST_METHOD),
+                    Some(method) if method == Method::POST.as_str()
+                ) =>
+            {
+                origin
+            }
+            _ => {
+                *http_response.status_mut() = StatusCode::FORBIDDEN;
+                http_response
+                    .headers_mut()
+                    .insert(VARY, HeaderValue::from_static("Origin"));
+                return http_response;
+            }
+        };
+
+        *http_response.status_mut() = StatusCode::NO_CONTENT;
+        let header_map = http_response.headers_mut();
+        self.add_origin_headers(header_map, origin);
+        header_map.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static(ALLOWED_METHODS),
+        );
+
+        // NOTE: a literal `*` is not honoured for credentialed requests,
+        // so the requested headers are echoed instead
+        let allowed_headers = if self.allowed_headers.iter().any(|name| name == "*") {
+            request_headers.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned()
+        } else {
+            HeaderValue::from_str(&self.allowed_headers.join(", ")).ok()
+        };
+        if let Some(allowed_headers) = allowed_headers {
+            header_map.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+        }
+
+        if let Some(max_age) = self.max_age {
+            header_map.insert(ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+        }
+        http_response
+    }
+
+    /// Add the headers allowing the request `origin` to read the response.
+    pub fn add_headers(
+        &self,
+        origin: Option<&HeaderValue>,
+        http_response: &mut HttpResponse<Body>,
+    ) {
+        let header_map = http_response.headers_mut();
+        match self.allowed_origin(origin) {
+            Some(origin) => {
+                self.add_origin_headers(header_map, origin);
+                header_map.insert(ACCESS_CONTROL_EXPOSE_HEADERS, self.exposed_headers.clone());
+            }
+            None => {
+                header_map.insert(VARY, HeaderValue::from_static("Origin"));
+            }
+        }
+    }
+
+    fn add_origin_headers(&self, header_map: &mut HeaderMap, origin: HeaderValue) {
+        let any_origin = origin == ANY_ORIGIN;
+        header_map.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        header_map.insert(VARY, HeaderValue::from_static("Origin"));
+        if self.allow_credentials && !any_origin {
+            header_map.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors() -> Cors {
+        Cors::new(
+            vec![
+                "https://example.com".to_string(),
+                "https://*.example.org".to_string(),
+            ],
+            vec!["*".to_string()],
+            vec!["x-custom".to_string()],
+            true,
+            Some(Duration::from_secs(600)),
+        )
+    }
+
+    fn preflight(origin: &str, method: &str) -> HttpRequest<Body> {
+        HttpRequest::builder()
+            .method(Method::OPTIONS)
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, method)
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "content-type, x-grpc-web")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn should_match_origins() {
+        let cors = cors();
+        assert!(cors.is_allowed("https://example.com"));
+        assert!(cors.is_allowed("https://app.example.org"));
+        assert!(!cors.is_allowed("https://example.org"));
+        assert!(!cors.is_allowed("https://example.com.evil.com"));
+        assert!(!cors.is_allowed("http://example.com"));
+
+        let cors = Cors::new(vec!["*".to_string()], vec![], vec![], false, None);
+        assert!(cors.is_allowed("http://localhost:3000"));
+    }
+
+    #[test]
+    fn should_answer_preflight() {
+        let cors = cors();
+        let http_request = preflight("https://app.example.org", "POST");
+        assert!(Cors::is_preflight(&http_request));
+
+        let http_response = cors.preflight_response(&http_request);
+        let headers = http_response.headers();
+        assert_eq!(http_response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.org"
+        );
+        assert_eq!(headers[VARY], "Origin");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], ALLOWED_METHODS);
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type, x-grpc-web"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+
+        for http_request in vec![
+            preflight("https://evil.com", "POST"),
+            preflight("https://example.com", "DELETE"),
+        ] {
+            let http_response = cors.preflight_response(&http_request);
+            assert_eq!(http_response.status(), StatusCode::FORBIDDEN);
+            assert!(!http_response
+                .headers()
+                .contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        }
+
+        // NOTE: not a preflight, but callers may not have checked
+        let http_request = HttpRequest::builder()
+            .method(Method::OPTIONS)
+            .header(ORIGIN, "https://example.com")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!Cors::is_preflight(&http_request));
+        let http_response = cors.preflight_response(&http_request);
+        assert_eq!(http_response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn should_add_headers() {
+        let cors = cors();
+        let http_request = HttpRequest::builder()
+            .method(Method::POST)
+            .header(ORIGIN, "https://example.com")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!Cors::is_preflight(&http_request));
+
+        let mut http_response = HttpResponse::new(Body::empty());
+        cors.add_headers(http_request.headers().get(ORIGIN), &mut http_response);
+        let headers = http_response.headers();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+        assert_eq!(
+            headers[ACCESS_CONTROL_EXPOSE_HEADERS],
+            "grpc-status, grpc-message, grpc-status-details-bin, grpc-encoding, \
+             grpc-accept-encoding, x-custom"
+        );
+
+        let mut http_response = HttpResponse::new(Body::empty());
+        cors.add_headers(None, &mut http_response);
+        assert!(!http_response
+            .headers()
+            .contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(http_response.headers()[VARY], "Origin");
+    }
+
+    #[test]
+    fn should_not_allow_credentials_for_any_origin() {
+        let cors = Cors::new(
+            vec!["https://example.com".to_string(), "*".to_string()],
+            vec![],
+            vec![],
+            true,
+            None,
+        );
+
+        let mut http_response = HttpResponse::new(Body::empty());
+        cors.add_headers(
+            Some(&HeaderValue::from_static("https://evil.com")),
+            &mut http_response,
+        );
+        let headers = http_response.headers();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+
+        let http_response = cors.preflight_response(&preflight("https://evil.com", "POST"));
+        let headers = http_response.headers();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+
+        let mut http_response = HttpResponse::new(Body::empty());
+        cors.add_headers(
+            Some(&HeaderValue::from_static("https://example.com")),
+            &mut http_response,
+        );
+        let headers = http_response.headers();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    }
+}
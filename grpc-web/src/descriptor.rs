@@ -0,0 +1,322 @@
+use crate::Error;
+use prost_types::{FileDescriptorProto, MethodDescriptorProto, ServiceDescriptorProto};
+
+/// Parse the package, imports and services of a `.proto` source. Messages,
+/// enums and options are skipped, as only the shape of each method is
+/// needed to forward calls.
+pub(crate) fn parse_proto(name: &str, source: &str) -> Result<FileDescriptorProto, Error> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+    };
+    let mut file = FileDescriptorProto {
+        name: Some(name.to_string()),
+        ..Default::default()
+    };
+
+    while let Some(token) = parser.next() {
+        match token.as_str() {
+            ";" => (),
+            "syntax" | "edition" | "option" => parser.skip_statement()?,
+            "package" => {
+                file.package = Some(parser.ident()?);
+                parser.expect(";")?;
+            }
+            "import" => {
+                let mut path = parser.token()?;
+                if path == "public" || path == "weak" {
+                    path = parser.token()?;
+                }
+                file.dependency.push(unquote(&path)?);
+                parser.expect(";")?;
+            }
+            "message" | "enum" | "extend" => {
+                parser.ident()?;
+                parser.expect("{")?;
+                parser.skip_block()?;
+            }
+            "service" => file.service.push(parser.service()?),
+            token => return Err(unexpected(token)),
+        }
+    }
+    Ok(file)
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn token(&mut self) -> Result<String, Error> {
+        self.next()
+            .ok_or_else(|| Error::InvalidProto("unexpected end of file".to_string()))
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        let token = self.token()?;
+        if is_ident(&token) {
+            Ok(token)
+        } else {
+            Err(unexpected(&token))
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), Error> {
+        let token = self.token()?;
+        if token == expected {
+            Ok(())
+        } else {
+            Err(Error::InvalidProto(format!(
+                "expected `{}`, found `{}`",
+                expected, token
+            )))
+        }
+    }
+
+    /// Skip to the end of a block, after its opening brace.
+    fn skip_block(&mut self) -> Result<(), Error> {
+        let mut depth = 1;
+        while depth > 0 {
+            match self.token()?.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Skip to the end of a statement, which may contain an aggregate
+    /// value such as `option (a) = { b: 1 };`.
+    fn skip_statement(&mut self) -> Result<(), Error> {
+        loop {
+            match self.token()?.as_str() {
+                ";" => return Ok(()),
+                "{" => self.skip_block()?,
+                _ => (),
+            }
+        }
+    }
+
+    fn service(&mut self) -> Result<ServiceDescriptorProto, Error> {
+        let mut service = ServiceDescriptorProto {
+            name: Some(self.ident()?),
+            ..Default::default()
+        };
+        self.expect("{")?;
+        loop {
+            match self.token()?.as_str() {
+                "}" => return Ok(service),
+                ";" => (),
+                "option" => self.skip_statement()?,
+                "rpc" => service.method.push(self.method()?),
+                token => return Err(unexpected(token)),
+            }
+        }
+    }
+
+    fn method(&mut self) -> Result<MethodDescriptorProto, Error> {
+        let name = self.ident()?;
+        let (client_streaming, input_type) = self.method_type()?;
+        self.expect("returns")?;
+        let (server_streaming, output_type) = self.method_type()?;
+        match self.token()?.as_str() {
+            ";" => (),
+            "{" => self.skip_block()?,
+            token => return Err(unexpected(token)),
+        }
+
+        Ok(MethodDescriptorProto {
+            name: Some(name),
+            input_type: Some(input_type),
+            output_type: Some(output_type),
+            client_streaming: Some(true).filter(|_| client_streaming),
+            server_streaming: Some(true).filter(|_| server_streaming),
+            ..Default::default()
+        })
+    }
+
+    /// A request or response type such as `(stream HelloRequest)`.
+    fn method_type(&mut self) -> Result<(bool, String), Error> {
+        self.expect("(")?;
+        // NOTE: a message may itself be called `stream`
+        let streaming = self.peek() == Some("stream")
+            && self.tokens.get(self.pos + 1).map(String::as_str) != Some(")");
+        if streaming {
+            self.pos += 1;
+        }
+        let name = self.ident()?;
+        self.expect(")")?;
+        Ok((streaming, name))
+    }
+}
+
+/// Split a source into identifiers, string literals and punctuation,
+/// dropping whitespace and comments.
+fn tokenize(source: &str) -> Result<Vec<String>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => (),
+            '/' if chars.peek() == Some(&'/') => {
+                chars.by_ref().find(|c| *c == '\n');
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if last == '*' => break,
+                        Some(c) => last = c,
+                        None => {
+                            return Err(Error::InvalidProto("unterminated comment".to_string()))
+                        }
+                    }
+                }
+            }
+            '"' | '\'' => {
+                let mut token = c.to_string();
+                loop {
+                    match chars.next() {
+                        Some('\\') => {
+                            token.push('\\');
+                            token.extend(chars.next());
+                        }
+                        Some(end) if end == c => break,
+                        Some(c) => token.push(c),
+                        None => return Err(Error::InvalidProto("unterminated string".to_string())),
+                    }
+                }
+                token.push(c);
+                tokens.push(token);
+            }
+            c if is_ident_char(c) => {
+                let mut token = c.to_string();
+                while let Some(c) = chars.peek().copied().filter(|c| is_ident_char(*c)) {
+                    token.push(c);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+            c => tokens.push(c.to_string()),
+        }
+    }
+    Ok(tokens)
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn is_ident(token: &str) -> bool {
+    token.chars().all(is_ident_char)
+}
+
+fn unquote(token: &str) -> Result<String, Error> {
+    match token.chars().next() {
+        Some('"') | Some('\'') if token.len() >= 2 => Ok(token[1..token.len() - 1].to_string()),
+        _ => Err(unexpected(token)),
+    }
+}
+
+fn unexpected(token: &str) -> Error {
+    Error::InvalidProto(format!("unexpected `{}`", token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GREETER: &str = r#"
+        syntax = "proto3";
+        import public "google/api/annotations.proto";
+        import "common.proto";
+
+        package helloworld; // trailing comment
+
+        option go_package = "example.com/helloworld;helloworld";
+
+        /* The greeting service { not a block } */
+        service Greeter {
+            option deprecated = false;
+            rpc SayHello (HelloRequest) returns (HelloReply) {
+                option (google.api.http) = { post: "/v1/hello" body: "*" };
+            }
+            rpc SayRepeatHello (HelloRequest) returns (stream .helloworld.HelloReply);
+            rpc Chat (stream HelloRequest) returns (stream HelloReply) {}
+        }
+
+        message HelloRequest {
+            string name = 1;
+            map<string, string> labels = 2;
+            message Nested { enum Kind { A = 0; } }
+        }
+
+        message HelloReply { string message = 1 [json_name = "msg"]; }
+    "#;
+
+    #[test]
+    fn should_parse_services() -> Result<(), Error> {
+        let file = parse_proto("greeter.proto", GREETER)?;
+        assert_eq!(file.name(), "greeter.proto");
+        assert_eq!(file.package(), "helloworld");
+        assert_eq!(
+            file.dependency,
+            vec![
+                "google/api/annotations.proto".to_string(),
+                "common.proto".to_string()
+            ]
+        );
+
+        assert_eq!(file.service.len(), 1);
+        let methods = &file.service[0].method;
+        assert_eq!(file.service[0].name(), "Greeter");
+        assert_eq!(
+            methods
+                .iter()
+                .map(|method| (
+                    method.name(),
+                    method.client_streaming,
+                    method.server_streaming
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("SayHello", None, None),
+                ("SayRepeatHello", None, Some(true)),
+                ("Chat", Some(true), Some(true)),
+            ]
+        );
+        assert_eq!(methods[1].output_type(), ".helloworld.HelloReply");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_invalid_proto() {
+        for source in &[
+            "service Greeter { rpc SayHello (HelloRequest) returns HelloReply; }",
+            "service Greeter { rpc SayHello (HelloRequest) returns (HelloReply)",
+            "message HelloRequest { string name = 1; ",
+            "import common.proto;",
+            "/* unterminated",
+            "}",
+        ] {
+            assert!(matches!(
+                parse_proto("invalid.proto", source),
+                Err(Error::InvalidProto(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use crate::Error;
+use hyper::http::Uri;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use trust_dns_resolver::config::{
+    LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts,
+};
+use trust_dns_resolver::system_conf::read_system_conf;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Time between two discoveries of the replicas of an upstream.
+pub const DEFAULT_DISCOVERY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Source of the replicas of an upstream, which may change at runtime.
+#[tonic::async_trait]
+pub trait Discovery: Send + Sync + 'static {
+    /// Addresses of the current replicas.
+    async fn discover(&self) -> Result<Vec<Uri>, Error>;
+}
+
+#[tonic::async_trait]
+impl<D: Discovery + ?Sized> Discovery for Arc<D> {
+    async fn discover(&self) -> Result<Vec<Uri>, Error> {
+        self.as_ref().discover().await
+    }
+}
+
+enum DnsQuery {
+    /// A and AAAA records, served on the same port.
+    Host { host: String, port: u16 },
+    /// SRV records, naming the host and port of each replica.
+    Srv(String),
+}
+
+/// Replicas found by resolving DNS records, e.g. those of a headless
+/// Kubernetes service.
+pub struct DnsDiscovery {
+    resolver: TokioAsyncResolver,
+    query: DnsQuery,
+}
+
+impl DnsDiscovery {
+    /// Every address `host` resolves to, connecting on `port`.
+    pub fn host(host: impl Into<String>, port: u16) -> Result<Self, Error> {
+        Ok(Self {
+            resolver: system_resolver()?,
+            query: DnsQuery::Host {
+                host: host.into(),
+                port,
+            },
+        })
+    }
+
+    /// Every target of the SRV records of `name`, such as
+    /// `_grpc._tcp.greeter.default.svc.cluster.local`.
+    pub fn srv(name: impl Into<String>) -> Result<Self, Error> {
+        Ok(Self {
+            resolver: system_resolver()?,
+            query: DnsQuery::Srv(name.into()),
+        })
+    }
+
+    /// Ask the name server at `addr`, rather than the system ones.
+    pub fn name_server(mut self, addr: SocketAddr) -> Result<Self, Error> {
+        let config = ResolverConfig::from_parts(
+            None,
+            Vec::new(),
+            NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true),
+        );
+        self.resolver = resolver(config, ResolverOpts::default())?;
+        Ok(self)
+    }
+
+    async fn lookup(&self, host: &str, port: u16) -> Result<Vec<Uri>, Error> {
+        self.resolver
+            .lookup_ip(host)
+            .await?
+            .iter()
+            .map(|ip| {
+                let addr = SocketAddr::new(ip, port);
+                format!("http://{}", addr)
+                    .parse()
+                    .map_err(|_| Error::InvalidEndpoints(addr.to_string()))
+            })
+            .collect()
+    }
+}
+
+fn system_resolver() -> Result<TokioAsyncResolver, Error> {
+    let (config, options) = read_system_conf()?;
+    resolver(config, options)
+}
+
+fn resolver(
+    config: ResolverConfig,
+    mut options: ResolverOpts,
+) -> Result<TokioAsyncResolver, Error> {
+    // NOTE: replicas may listen on either family, so both are looked up
+    options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    Ok(TokioAsyncResolver::tokio(config, options)?)
+}
+
+#[tonic::async_trait]
+impl Discovery for DnsDiscovery {
+    async fn discover(&self) -> Result<Vec<Uri>, Error> {
+        match &self.query {
+            DnsQuery::Host { host, port } => self.lookup(host, *port).await,
+            DnsQuery::Srv(name) => {
+                let mut uris = Vec::new();
+                for srv in self.resolver.srv_lookup(name.as_str()).await?.iter() {
+                    uris.extend(self.lookup(&srv.target().to_utf8(), srv.port()).await?);
+                }
+                Ok(uris)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct EndpointsFile {
+    endpoints: Vec<String>,
+}
+
+/// Replicas listed in a file, which is read again on every discovery so
+/// that it can be rewritten at runtime. Files ending in `.toml` are read as
+/// TOML, anything else as JSON, e.g.
+///
+/// ```json
+/// { "endpoints": ["http://10.0.0.1:50052", "http://10.0.0.2:50052"] }
+/// ```
+pub struct FileDiscovery {
+    path: PathBuf,
+}
+
+impl FileDiscovery {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn parse(&self, contents: &str) -> Result<EndpointsFile, String> {
+        match self
+            .path
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some("toml") => toml::from_str(contents).map_err(|err| err.to_string()),
+            _ => serde_json::from_str(contents).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Discovery for FileDiscovery {
+    async fn discover(&self) -> Result<Vec<Uri>, Error> {
+        let contents = tokio::fs::read_to_string(&self.path).await?;
+        let file = self
+            .parse(&contents)
+            .map_err(|err| Error::InvalidEndpoints(format!("{}: {}", self.path.display(), err)))?;
+        file.endpoints
+            .iter()
+            .map(|endpoint| {
+                endpoint
+                    .parse()
+                    .map_err(|_| Error::InvalidEndpoints(endpoint.clone()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_path;
+    use std::net::Ipv4Addr;
+    use trust_dns_resolver::proto::op::{Message, MessageType};
+    use trust_dns_resolver::proto::rr::rdata::SRV;
+    use trust_dns_resolver::proto::rr::{Name, RData, Record, RecordType};
+
+    #[tokio::test]
+    async fn should_read_endpoints_file() -> Result<(), Error> {
+        let json = temp_path("endpoints.json");
+        std::fs::write(&json, r#"{"endpoints": ["http://10.0.0.1:50052"]}"#)?;
+        let toml = temp_path("endpoints.toml");
+        std::fs::write(
+            &toml,
+            r#"endpoints = ["http://10.0.0.1:50052", "http://10.0.0.2:50052"]"#,
+        )?;
+
+        assert_eq!(
+            FileDiscovery::new(&json).discover().await?,
+            vec![Uri::from_static("http://10.0.0.1:50052")]
+        );
+        assert_eq!(
+            FileDiscovery::new(&toml).discover().await?,
+            vec![
+                Uri::from_static("http://10.0.0.1:50052"),
+                Uri::from_static("http://10.0.0.2:50052")
+            ]
+        );
+
+        // NOTE: e.g. a file which is only partially written
+        std::fs::write(&json, r#"{"endpoints": ["#)?;
+        assert!(matches!(
+            FileDiscovery::new(&json).discover().await,
+            Err(Error::InvalidEndpoints(_))
+        ));
+
+        std::fs::remove_file(json)?;
+        std::fs::remove_file(toml)?;
+        Ok(())
+    }
+
+    /// Name server answering every A query with `127.0.0.1`, and SRV
+    /// queries with two targets.
+    async fn serve_dns() -> SocketAddr {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_desired(request.recursion_desired())
+                    .set_recursion_available(true);
+                for query in request.queries() {
+                    response.add_query(query.clone());
+                    let name = query.name().clone();
+                    let answers = match query.query_type() {
+                        RecordType::A => {
+                            vec![RData::A(Ipv4Addr::LOCALHOST)]
+                        }
+                        RecordType::SRV => vec![
+                            RData::SRV(SRV::new(0, 0, 50052, Name::from_ascii("a.test.").unwrap())),
+                            RData::SRV(SRV::new(0, 0, 50053, Name::from_ascii("b.test.").unwrap())),
+                        ],
+                        _ => Vec::new(),
+                    };
+                    for rdata in answers {
+                        response.add_answer(Record::from_rdata(name.clone(), 0, rdata));
+                    }
+                }
+                socket
+                    .send_to(&response.to_vec().unwrap(), src)
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn should_resolve_dns() -> Result<(), Error> {
+        let addr = serve_dns().await;
+
+        let discovery = DnsDiscovery::host("greeter.test.", 50052)?.name_server(addr)?;
+        assert_eq!(
+            discovery.discover().await?,
+            vec![Uri::from_static("http://127.0.0.1:50052")]
+        );
+
+        let discovery = DnsDiscovery::srv("_grpc._tcp.greeter.test.")?.name_server(addr)?;
+        assert_eq!(
+            discovery.discover().await?,
+            vec![
+                Uri::from_static("http://127.0.0.1:50052"),
+                Uri::from_static("http://127.0.0.1:50053")
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -1,10 +1,14 @@
+use crate::{GrpcWebResponse, Mode};
 use base64::DecodeError as Base64DecodeError;
-use hyper::Error as HyperError;
+use hyper::http::StatusCode;
+use hyper::{Body, Error as HyperError, Response as HttpResponse};
 use prost::DecodeError as ProstDecodeError;
+use std::io::Error as IoError;
 use std::num::TryFromIntError;
 use thiserror::Error;
 use tonic::transport::Error as TransportError;
-use tonic::Status;
+use tonic::{Code, Status};
+use trust_dns_resolver::error::ResolveError;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -12,6 +16,10 @@ pub enum Error {
     NoServices,
     #[error("No response")]
     NoResponse,
+    #[error("Upstream is not ready")]
+    NotReady,
+    #[error("No healthy replicas")]
+    NoHealthyReplicas,
     #[error("Invalid request")]
     InvalidRequest,
     #[error("Invalid query")]
@@ -22,6 +30,26 @@ pub enum Error {
     UnknownService,
     #[error("Unknown method")]
     UnknownMethod,
+    #[error("Missing descriptor: {0}")]
+    MissingDescriptor(String),
+    #[error("Invalid proto: {0}")]
+    InvalidProto(String),
+    #[error("Invalid endpoints: {0}")]
+    InvalidEndpoints(String),
+    #[error("Invalid TLS config: {0}")]
+    InvalidTlsConfig(String),
+    #[error("Truncated frame")]
+    TruncatedFrame,
+    #[error("Invalid frame flags: {0:#04x}")]
+    InvalidFrameFlags(u8),
+    #[error("Invalid timeout")]
+    InvalidTimeout,
+    #[error("Invalid trailers")]
+    InvalidTrailers,
+    #[error("Unsupported encoding: {0}")]
+    UnsupportedEncoding(String),
+    #[error("Message larger than {0} bytes")]
+    MessageTooLarge(usize),
 
     #[error("HyperError: {0}")]
     HyperError(#[from] HyperError),
@@ -35,4 +63,128 @@ pub enum Error {
     Status(#[from] Status),
     #[error("TryFromIntError: {0}")]
     TryFromIntError(#[from] TryFromIntError),
+    #[error("IoError: {0}")]
+    IoError(#[from] IoError),
+    #[error("ResolveError: {0}")]
+    ResolveError(#[from] ResolveError),
+}
+
+impl Error {
+    /// HTTP status to answer with. Anything that was understood as a
+    /// gRPC-Web call is reported with `200 OK` and a `grpc-status`.
+    pub fn http_status(&self) -> StatusCode {
+        match self {
+            Error::InvalidRequest => StatusCode::BAD_REQUEST,
+            _ => StatusCode::OK,
+        }
+    }
+
+    pub fn code(&self) -> Code {
+        match self {
+            Error::NoServices
+            | Error::NoResponse
+            | Error::NotReady
+            | Error::NoHealthyReplicas
+            | Error::TransportError(_)
+            | Error::ResolveError(_) => Code::Unavailable,
+            Error::InvalidRequest => Code::Internal,
+            Error::InvalidQuery
+            | Error::Unsupported
+            | Error::UnknownService
+            | Error::UnknownMethod
+            | Error::UnsupportedEncoding(_) => Code::Unimplemented,
+            Error::TruncatedFrame
+            | Error::InvalidFrameFlags(_)
+            | Error::InvalidTimeout
+            | Error::Base64DecodeError(_) => Code::InvalidArgument,
+            Error::MissingDescriptor(_)
+            | Error::InvalidProto(_)
+            | Error::InvalidEndpoints(_)
+            | Error::InvalidTlsConfig(_)
+            | Error::InvalidTrailers
+            | Error::HyperError(_)
+            | Error::ProstDecodeError(_)
+            | Error::TryFromIntError(_)
+            | Error::IoError(_) => Code::Internal,
+            Error::MessageTooLarge(_) => Code::ResourceExhausted,
+            Error::Status(status) => status.code(),
+        }
+    }
+
+    /// Trailers-only gRPC-Web response describing this error.
+    pub fn into_http_response(self, mode: Mode) -> HttpResponse<Body> {
+        let http_status = self.http_status();
+        let mut http_response = GrpcWebResponse::from(Status::from(self)).into_http_response(mode);
+        *http_response.status_mut() = http_status;
+        http_response
+    }
+}
+
+impl From<Error> for Status {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Status(status) => status,
+            err => Status::new(err.code(), err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_map_errors() {
+        for (err, http_status, code) in vec![
+            (Error::NoServices, StatusCode::OK, Code::Unavailable),
+            (Error::NotReady, StatusCode::OK, Code::Unavailable),
+            (Error::NoHealthyReplicas, StatusCode::OK, Code::Unavailable),
+            (
+                Error::InvalidRequest,
+                StatusCode::BAD_REQUEST,
+                Code::Internal,
+            ),
+            (Error::UnknownService, StatusCode::OK, Code::Unimplemented),
+            (Error::UnknownMethod, StatusCode::OK, Code::Unimplemented),
+            (Error::TruncatedFrame, StatusCode::OK, Code::InvalidArgument),
+            (
+                Error::UnsupportedEncoding("br".to_string()),
+                StatusCode::OK,
+                Code::Unimplemented,
+            ),
+            (
+                Error::MessageTooLarge(4),
+                StatusCode::OK,
+                Code::ResourceExhausted,
+            ),
+            (
+                base64::decode("?").unwrap_err().into(),
+                StatusCode::OK,
+                Code::InvalidArgument,
+            ),
+            (
+                Status::permission_denied("denied").into(),
+                StatusCode::OK,
+                Code::PermissionDenied,
+            ),
+        ] {
+            let http_response = err.into_http_response(Mode::Binary);
+            assert_eq!(http_response.status(), http_status);
+            assert_eq!(
+                http_response.headers()["grpc-status"],
+                (code as i32).to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn should_keep_upstream_status() {
+        let status: Status = Error::Status(Status::not_found("missing")).into();
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.message(), "missing");
+
+        let status: Status = Error::UnknownMethod.into();
+        assert_eq!(status.code(), Code::Unimplemented);
+        assert_eq!(status.message(), "Unknown method");
+    }
 }
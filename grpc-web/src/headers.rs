@@ -0,0 +1,202 @@
+use crate::GRPC_TIMEOUT_HEADER;
+use hyper::http::{header::CONNECTION, HeaderMap};
+use std::collections::HashSet;
+use tonic::metadata::{
+    AsciiMetadataKey, AsciiMetadataValue, BinaryMetadataKey, BinaryMetadataValue, MetadataMap,
+};
+
+/// Hop-by-hop headers, headers only meaningful to the browser's HTTP/1
+/// connection and headers describing the gRPC-Web framing itself. None of
+/// these are forwarded as metadata.
+const NON_METADATA_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "content-type",
+    "content-length",
+    "accept",
+    "accept-encoding",
+    "accept-language",
+    "access-control-request-headers",
+    "access-control-request-method",
+    "cache-control",
+    "dnt",
+    "origin",
+    "pragma",
+    "referer",
+    "user-agent",
+    "x-grpc-web",
+    "x-user-agent",
+    "grpc-encoding",
+    "grpc-accept-encoding",
+];
+
+/// Headers the proxy itself acts upon, which are forwarded whatever the
+/// filter says.
+const PROTOCOL_HEADERS: &[&str] = &[GRPC_TIMEOUT_HEADER];
+
+/// Prefix of the fetch metadata headers set by browsers.
+const BROWSER_HEADER_PREFIX: &str = "sec-";
+
+/// Convert the headers of a gRPC-Web request into metadata for the
+/// upstream call. Values of `-bin` headers are base64 decoded, and
+/// headers which cannot be represented as metadata are dropped.
+pub fn headers_to_metadata(headers: &HeaderMap) -> MetadataMap {
+    // NOTE: headers named in `Connection` are hop-by-hop as well
+    let connection: HashSet<String> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+
+    let mut metadata = MetadataMap::new();
+    for (name, value) in headers.iter() {
+        let name = name.as_str();
+        if NON_METADATA_HEADERS.contains(&name)
+            || name.starts_with(BROWSER_HEADER_PREFIX)
+            || connection.contains(name)
+        {
+            continue;
+        }
+
+        if name.ends_with("-bin") {
+            match (
+                BinaryMetadataKey::from_bytes(name.as_bytes()),
+                base64::decode(value.as_bytes()),
+            ) {
+                (Ok(key), Ok(value)) => {
+                    metadata.append_bin(key, BinaryMetadataValue::from_bytes(&value));
+                }
+                _ => log::debug!("Dropping invalid binary header: {}", name),
+            }
+        } else {
+            match (
+                AsciiMetadataKey::from_bytes(name.as_bytes()),
+                value.to_str().map(AsciiMetadataValue::from_str),
+            ) {
+                (Ok(key), Ok(Ok(value))) => {
+                    metadata.append(key, value);
+                }
+                _ => log::debug!("Dropping invalid header: {}", name),
+            }
+        }
+    }
+    metadata
+}
+
+/// Allow and deny lists deciding which request headers reach the
+/// upstream. Entries are header names, or prefixes ending in `*`.
+/// `grpc-timeout` is never filtered, since the proxy enforces it.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl HeaderFilter {
+    /// An empty allow list forwards every header that is not denied.
+    pub fn new(allow: Vec<String>, deny: Vec<String>) -> Self {
+        let lowercase = |patterns: Vec<String>| {
+            patterns
+                .into_iter()
+                .map(|pattern| pattern.trim().to_ascii_lowercase())
+                .filter(|pattern| !pattern.is_empty())
+                .collect()
+        };
+        Self {
+            allow: lowercase(allow),
+            deny: lowercase(deny),
+        }
+    }
+
+    pub fn is_allowed(&self, name: &str) -> bool {
+        let matches = |pattern: &String| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        };
+        (self.allow.is_empty() || self.allow.iter().any(matches)) && !self.deny.iter().any(matches)
+    }
+
+    pub fn apply(&self, metadata: &mut MetadataMap) {
+        let headers = std::mem::take(metadata).into_headers();
+        let mut filtered = HeaderMap::with_capacity(headers.len());
+        for (name, value) in headers.iter() {
+            if PROTOCOL_HEADERS.contains(&name.as_str()) || self.is_allowed(name.as_str()) {
+                filtered.append(name.clone(), value.clone());
+            }
+        }
+        *metadata = MetadataMap::from_headers(filtered);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::http::HeaderValue;
+
+    #[test]
+    fn should_convert_headers_to_metadata() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer token"));
+        headers.insert("x-tenant-id", HeaderValue::from_static("tenant"));
+        headers.insert("x-trace-bin", HeaderValue::from_static("AQID"));
+        headers.insert("x-broken-bin", HeaderValue::from_static("!!"));
+        headers.insert("x-hop", HeaderValue::from_static("hop"));
+        headers.insert("connection", HeaderValue::from_static("keep-alive, X-Hop"));
+        headers.insert(
+            "content-type",
+            HeaderValue::from_static("application/grpc-web"),
+        );
+        headers.insert("sec-fetch-mode", HeaderValue::from_static("cors"));
+        headers.insert("grpc-timeout", HeaderValue::from_static("1S"));
+
+        let metadata = headers_to_metadata(&headers);
+        assert_eq!(metadata.len(), 4);
+        assert_eq!(metadata.get("authorization").unwrap(), "Bearer token");
+        assert_eq!(metadata.get("x-tenant-id").unwrap(), "tenant");
+        assert_eq!(metadata.get("grpc-timeout").unwrap(), "1S");
+        assert_eq!(
+            metadata.get_bin("x-trace-bin").unwrap().to_bytes().unwrap(),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn should_filter_headers() {
+        let filter = HeaderFilter::default();
+        assert!(filter.is_allowed("authorization"));
+
+        let filter = HeaderFilter::new(
+            vec!["Authorization".to_string(), "x-*".to_string()],
+            vec!["x-internal-*".to_string()],
+        );
+        assert!(filter.is_allowed("authorization"));
+        assert!(filter.is_allowed("x-tenant-id"));
+        assert!(!filter.is_allowed("x-internal-token"));
+        assert!(!filter.is_allowed("cookie"));
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert("authorization", "Bearer token".parse().unwrap());
+        metadata.insert("cookie", "a=b".parse().unwrap());
+        metadata.insert("x-internal-token", "secret".parse().unwrap());
+        metadata.insert("grpc-timeout", "1S".parse().unwrap());
+        filter.apply(&mut metadata);
+        assert_eq!(metadata.len(), 2);
+        assert!(metadata.contains_key("authorization"));
+        assert!(metadata.contains_key("grpc-timeout"));
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert("grpc-timeout", "1S".parse().unwrap());
+        HeaderFilter::new(vec![], vec!["grpc-*".to_string()]).apply(&mut metadata);
+        assert!(metadata.contains_key("grpc-timeout"));
+    }
+}
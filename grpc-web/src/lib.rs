@@ -1,11 +1,56 @@
+mod balance;
+mod channel;
+mod client;
 mod codec;
+mod compression;
+mod cors;
+mod descriptor;
+mod discovery;
 mod error;
+mod headers;
 mod metadata;
+mod mode;
+mod proxy;
 mod request;
 mod response;
+mod route;
+mod service;
+mod store;
+#[cfg(test)]
+mod test_util;
+mod timeout;
+mod tls;
 
+pub use balance::LoadBalancing;
+pub use channel::unix_socket_uri;
+pub use client::{GrpcWebClientBody, GrpcWebClientService};
 pub use codec::ProxyCodec;
+pub use compression::{
+    Encoding, DEFAULT_MAX_MESSAGE_SIZE, GRPC_ACCEPT_ENCODING_HEADER, GRPC_ENCODING_HEADER,
+    SUPPORTED_ENCODINGS,
+};
+pub use cors::Cors;
+pub use discovery::{Discovery, DnsDiscovery, FileDiscovery, DEFAULT_DISCOVERY_INTERVAL};
 pub use error::Error;
-pub use metadata::{ConnectionType, Metadata};
-pub use request::{GrpcRequest, GrpcWebRequest};
-pub use response::{GrpcResponse, GrpcWebResponse};
+pub use headers::{headers_to_metadata, HeaderFilter};
+pub use metadata::{ConnectionType, Metadata, ReflectionVersion};
+pub use mode::{
+    Mode, GRPC_CONTENT_TYPE, GRPC_WEB_CONTENT_TYPE, GRPC_WEB_CONTENT_TYPE_PROTO,
+    GRPC_WEB_TEXT_CONTENT_TYPE, GRPC_WEB_TEXT_CONTENT_TYPE_PROTO,
+};
+pub use proxy::{GrpcWebProxy, GrpcWebProxyBuilder};
+pub use request::{decode_frames, Frame, GrpcRequest, GrpcWebRequest, FRAME_HEADER_SIZE};
+#[allow(deprecated)]
+pub use response::GrpcResponse;
+pub use response::{
+    encode_streaming, status_to_metadata, streaming_http_response, GrpcStreamingResponse,
+    GrpcWebResponse,
+};
+pub use route::ServicePattern;
+pub use service::{GrpcWebLayer, GrpcWebService};
+pub use store::{MetadataStore, DEFAULT_MISS_REFRESH_INTERVAL};
+pub use timeout::{
+    format_grpc_timeout, parse_grpc_timeout, with_deadline, TimeoutPolicy, Timeouts,
+    GRPC_TIMEOUT_HEADER,
+};
+pub use tls::UpstreamTls;
@@ -1,24 +1,62 @@
+use crate::channel::{connect, UpstreamChannel};
+use crate::descriptor::parse_proto;
 use crate::error::Error;
 use futures::stream;
 use hyper::http::uri::PathAndQuery;
 use prost::Message;
-use prost_types::{FileDescriptorProto, MethodDescriptorProto};
+use prost_types::{FileDescriptorProto, FileDescriptorSet, MethodDescriptorProto};
 use std::collections::HashMap;
+use std::path::Path;
 use tokio_stream::StreamExt;
+use tonic::client::Grpc as GrpcClient;
+use tonic::codec::ProstCodec;
 use tonic::codegen::StdError;
-use tonic::transport::Channel;
+use tonic::transport::Endpoint;
 use tonic::Request as GrpcRequest;
+use tonic::{Code, Status};
 
-use proto::server_reflection_client::ServerReflectionClient;
-use proto::server_reflection_request::MessageRequest;
-use proto::server_reflection_response::MessageResponse;
-use proto::{ServerReflectionRequest, ServiceResponse};
+use proto::v1alpha::server_reflection_request::MessageRequest;
+use proto::v1alpha::server_reflection_response::MessageResponse;
+use proto::v1alpha::{ServerReflectionRequest, ServerReflectionResponse, ServiceResponse};
 
 pub mod proto {
-    tonic::include_proto!("grpc.reflection.v1alpha");
+    pub mod v1 {
+        tonic::include_proto!("grpc.reflection.v1");
+    }
+
+    pub mod v1alpha {
+        tonic::include_proto!("grpc.reflection.v1alpha");
+    }
+}
+
+/// Version of the reflection service that described the upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReflectionVersion {
+    V1,
+    V1Alpha,
+}
+
+impl ReflectionVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReflectionVersion::V1 => "grpc.reflection.v1",
+            ReflectionVersion::V1Alpha => "grpc.reflection.v1alpha",
+        }
+    }
+
+    fn path(&self) -> PathAndQuery {
+        match self {
+            ReflectionVersion::V1 => PathAndQuery::from_static(
+                "/grpc.reflection.v1.ServerReflection/ServerReflectionInfo",
+            ),
+            ReflectionVersion::V1Alpha => PathAndQuery::from_static(
+                "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo",
+            ),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ConnectionType {
     Unary,
     ClientStreaming,
@@ -49,47 +87,145 @@ impl From<MethodDescriptorProto> for ConnectionType {
     }
 }
 
-#[derive(Clone)]
-pub struct Metadata(HashMap<String, HashMap<String, ConnectionType>>);
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    services: HashMap<String, HashMap<String, ConnectionType>>,
+    reflection_version: Option<ReflectionVersion>,
+}
 
 impl Metadata {
+    /// Ask the upstream reflection service for the files defining its
+    /// services, along with their transitive dependencies. The `v1`
+    /// service is tried first, falling back to `v1alpha`.
     pub async fn from_reflection_service<D>(dst: D) -> Result<Self, Error>
     where
-        D: std::convert::TryInto<tonic::transport::Endpoint>,
+        D: std::convert::TryInto<Endpoint>,
         D::Error: Into<StdError>,
     {
-        let mut ref_client = ServerReflectionClient::connect(dst).await?;
-        let services = get_services(&mut ref_client).await?;
-        log::info!("Found {} services", services.len());
+        let channel = connect(&Endpoint::new(dst)?).await?;
+        let mut ref_client = ReflectionClient::new(channel, ReflectionVersion::V1);
+        let services = match ref_client.get_services().await {
+            Err(Error::Status(status)) if status.code() == Code::Unimplemented => {
+                log::debug!("{} is not available", ReflectionVersion::V1.as_str());
+                ref_client.version = ReflectionVersion::V1Alpha;
+                ref_client.get_services().await?
+            }
+            services => services?,
+        };
+        log::info!(
+            "Found {} services using {}",
+            services.len(),
+            ref_client.version.as_str()
+        );
 
-        let mut metadata = HashMap::new();
+        let mut files = HashMap::new();
+        for service in &services {
+            let request = MessageRequest::FileContainingSymbol(service.name.clone());
+            for file in ref_client.get_files(request).await? {
+                files.insert(file.name().to_string(), file);
+            }
+        }
+
+        let mut missing = missing_dependencies(&files);
+        while let Some(name) = missing.pop() {
+            let request = MessageRequest::FileByFilename(name.clone());
+            for file in ref_client.get_files(request).await? {
+                files.insert(file.name().to_string(), file);
+            }
+            if !files.contains_key(&name) {
+                return Err(Error::MissingDescriptor(name));
+            }
+            missing = missing_dependencies(&files);
+        }
+
+        let mut metadata = Self::from_files(files.values());
         for service in services {
-            let methods = get_methods(&mut ref_client, service.name.clone()).await?;
-            log::debug!("{:?}: {:?}", service, methods);
-
-            metadata.insert(
-                service.name,
-                methods
-                    .iter()
-                    .filter_map(|method| {
-                        if let Some(name) = method.name.clone() {
-                            Some((name, method.clone().into()))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect(),
-            );
+            if !metadata.services.contains_key(&service.name) {
+                return Err(Error::MissingDescriptor(service.name));
+            }
         }
+        metadata.reflection_version = Some(ref_client.version);
+        Ok(metadata)
+    }
+
+    /// Version of the reflection service these services were read from,
+    /// if any.
+    pub fn reflection_version(&self) -> Option<ReflectionVersion> {
+        self.reflection_version
+    }
+
+    /// Describe the services in a serialized `FileDescriptorSet`, such as
+    /// one written by `tonic_build`'s `file_descriptor_set_path`.
+    pub fn from_file_descriptor_set(bytes: &[u8]) -> Result<Self, Error> {
+        let descriptor_set = FileDescriptorSet::decode(bytes)?;
+        Ok(Self::from_files(&descriptor_set.file))
+    }
+
+    pub fn from_file_descriptor_set_path(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::from_file_descriptor_set(&std::fs::read(path)?)
+    }
+
+    /// Describe the services defined in a `.proto` source. Imports are not
+    /// followed, only the services in this file are added.
+    pub fn from_proto_source(name: &str, source: &str) -> Result<Self, Error> {
+        Ok(Self::from_files(vec![&parse_proto(name, source)?]))
+    }
+
+    pub fn from_proto_path(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        Self::from_proto_source(&path.to_string_lossy(), &std::fs::read_to_string(path)?)
+    }
+
+    /// Add the services described by `other`, replacing any with the same
+    /// name.
+    pub fn merge(mut self, other: Self) -> Self {
+        self.services.extend(other.services);
+        self.reflection_version = self.reflection_version.or(other.reflection_version);
+        self
+    }
+
+    /// Every known method by its path, e.g. `/helloworld.Greeter/SayHello`.
+    pub fn methods(&self) -> impl Iterator<Item = (String, ConnectionType)> + '_ {
+        self.services.iter().flat_map(|(service, methods)| {
+            methods.iter().map(move |(method, connection_type)| {
+                (format!("/{}/{}", service, method), connection_type.clone())
+            })
+        })
+    }
+
+    /// Index the methods of every service by the fully-qualified name of
+    /// the service.
+    pub(crate) fn from_files<'a>(files: impl IntoIterator<Item = &'a FileDescriptorProto>) -> Self {
+        let mut metadata = HashMap::new();
+        for file in files {
+            for service in &file.service {
+                let name = match file.package() {
+                    "" => service.name().to_string(),
+                    package => format!("{}.{}", package, service.name()),
+                };
+                log::debug!("{}: {:?}", name, service.method);
 
-        Ok(Self(metadata))
+                metadata.insert(
+                    name,
+                    service
+                        .method
+                        .iter()
+                        .map(|method| (method.name().to_string(), method.clone().into()))
+                        .collect(),
+                );
+            }
+        }
+        Self {
+            services: metadata,
+            reflection_version: None,
+        }
     }
 
     pub fn get_query_type(&self, path: PathAndQuery) -> Result<ConnectionType, Error> {
         let parts = path.path().split("/").collect::<Vec<&str>>();
         let parts = parts.get(1..3).ok_or(Error::InvalidQuery)?;
         let connection_type = self
-            .0
+            .services
             .get(parts[0])
             .ok_or(Error::UnknownService)?
             .get(parts[1])
@@ -99,65 +235,102 @@ impl Metadata {
     }
 }
 
-async fn get_services(
-    client: &mut ServerReflectionClient<Channel>,
-) -> Result<Vec<ServiceResponse>, Error> {
-    let request = ServerReflectionRequest {
-        host: "".to_string(),
-        message_request: Some(MessageRequest::ListServices(String::new())),
-    };
-
-    let request = GrpcRequest::new(stream::iter(vec![request]));
-    let mut inbound = client.server_reflection_info(request).await?.into_inner();
-    let response = inbound
-        .next()
-        .await
-        .ok_or(Error::NoResponse)??
-        .message_response
-        .ok_or(Error::NoResponse)?;
-
-    if let MessageResponse::ListServicesResponse(services) = response {
-        Ok(services.service)
-    } else {
-        Err(Error::NoServices)
-    }
+/// Dependencies of the known files which have not been fetched yet.
+fn missing_dependencies(files: &HashMap<String, FileDescriptorProto>) -> Vec<String> {
+    let mut missing: Vec<String> = files
+        .values()
+        .flat_map(|file| file.dependency.iter())
+        .filter(|name| !files.contains_key(*name))
+        .cloned()
+        .collect();
+    missing.sort();
+    missing.dedup();
+    missing
 }
 
-async fn get_methods(
-    client: &mut ServerReflectionClient<Channel>,
-    service_name: String,
-) -> Result<Vec<MethodDescriptorProto>, Error> {
-    let request = ServerReflectionRequest {
-        host: "".to_string(),
-        message_request: Some(MessageRequest::FileContainingSymbol(service_name)),
-    };
-    let request = GrpcRequest::new(stream::iter(vec![request]));
-    let mut inbound = client.server_reflection_info(request).await?.into_inner();
-
-    let response = inbound
-        .next()
-        .await
-        .ok_or(Error::NoResponse)??
-        .message_response
-        .ok_or(Error::NoResponse)?;
-
-    if let MessageResponse::FileDescriptorResponse(descriptor) = response {
-        let file_descriptor_proto = descriptor
-            .file_descriptor_proto
-            .first()
-            .expect("descriptor");
-
-        let service = FileDescriptorProto::decode(file_descriptor_proto.as_ref())?.service;
-        let service = service.first().expect("service");
-        Ok(service.method.clone())
-    } else {
-        Ok(vec![])
+/// Client for either version of the reflection service, which share the
+/// same messages.
+struct ReflectionClient {
+    client: GrpcClient<UpstreamChannel>,
+    version: ReflectionVersion,
+}
+
+impl ReflectionClient {
+    fn new(channel: UpstreamChannel, version: ReflectionVersion) -> Self {
+        Self {
+            client: GrpcClient::new(channel),
+            version,
+        }
+    }
+
+    async fn reflection_request(
+        &mut self,
+        message_request: MessageRequest,
+    ) -> Result<MessageResponse, Error> {
+        let request = ServerReflectionRequest {
+            host: "".to_string(),
+            message_request: Some(message_request),
+        };
+
+        self.client.ready().await?;
+        let codec = ProstCodec::<ServerReflectionRequest, ServerReflectionResponse>::default();
+        let request = GrpcRequest::new(stream::iter(vec![request]));
+        let mut inbound = self
+            .client
+            .streaming(request, self.version.path(), codec)
+            .await?
+            .into_inner();
+        let response = inbound
+            .next()
+            .await
+            .ok_or(Error::NoResponse)??
+            .message_response
+            .ok_or(Error::NoResponse)?;
+
+        match response {
+            MessageResponse::ErrorResponse(err) => {
+                Err(Status::new(Code::from_i32(err.error_code), err.error_message).into())
+            }
+            response => Ok(response),
+        }
+    }
+
+    async fn get_services(&mut self) -> Result<Vec<ServiceResponse>, Error> {
+        let request = MessageRequest::ListServices(String::new());
+        if let MessageResponse::ListServicesResponse(services) =
+            self.reflection_request(request).await?
+        {
+            Ok(services.service)
+        } else {
+            Err(Error::NoServices)
+        }
+    }
+
+    /// Every file descriptor in the response, which may lead with the
+    /// dependencies of the requested file.
+    async fn get_files(
+        &mut self,
+        request: MessageRequest,
+    ) -> Result<Vec<FileDescriptorProto>, Error> {
+        if let MessageResponse::FileDescriptorResponse(descriptor) =
+            self.reflection_request(request).await?
+        {
+            descriptor
+                .file_descriptor_proto
+                .iter()
+                .map(|file| Ok(FileDescriptorProto::decode(file.as_ref())?))
+                .collect()
+        } else {
+            Err(Error::NoResponse)
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::{method, serve_reflection, FakeReflection};
+    use prost_types::ServiceDescriptorProto;
 
     macro_rules! assert_err {
         ($result:expr, $err:pat) => {{
@@ -192,7 +365,10 @@ mod tests {
                 .collect(),
         );
 
-        let metadata = Metadata(metadata);
+        let metadata = Metadata {
+            services: metadata,
+            reflection_version: None,
+        };
         assert_ok!(metadata.get_query_type(PathAndQuery::from_static("/service/method")));
         assert_err!(
             metadata.get_query_type(PathAndQuery::from_static("/unknown/method")),
@@ -205,4 +381,131 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn should_index_every_service() {
+        let dependency = FileDescriptorProto {
+            name: Some("common.proto".to_string()),
+            package: Some("common".to_string()),
+            ..Default::default()
+        };
+        let file = FileDescriptorProto {
+            name: Some("greeter.proto".to_string()),
+            package: Some("helloworld".to_string()),
+            dependency: vec!["common.proto".to_string(), "other.proto".to_string()],
+            service: vec![
+                ServiceDescriptorProto {
+                    name: Some("Greeter".to_string()),
+                    method: vec![method("SayHello", false)],
+                    ..Default::default()
+                },
+                ServiceDescriptorProto {
+                    name: Some("Farewell".to_string()),
+                    method: vec![method("SayGoodbye", false), method("Wave", true)],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let metadata = Metadata::from_files(vec![&dependency, &file]);
+        assert_ok!(
+            metadata.get_query_type(PathAndQuery::from_static("/helloworld.Greeter/SayHello"))
+        );
+        assert!(matches!(
+            metadata.get_query_type(PathAndQuery::from_static("/helloworld.Farewell/Wave")),
+            Ok(ConnectionType::ServerStreaming)
+        ));
+        assert_err!(
+            metadata.get_query_type(PathAndQuery::from_static("/Greeter/SayHello")),
+            Error::UnknownService
+        );
+
+        let metadata = metadata.merge(Metadata::from_files(vec![&FileDescriptorProto {
+            package: Some("helloworld".to_string()),
+            service: vec![ServiceDescriptorProto {
+                name: Some("Greeter".to_string()),
+                method: vec![method("SayHello", true)],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }]));
+        assert!(matches!(
+            metadata.get_query_type(PathAndQuery::from_static("/helloworld.Greeter/SayHello")),
+            Ok(ConnectionType::ServerStreaming)
+        ));
+        assert_ok!(metadata.get_query_type(PathAndQuery::from_static("/helloworld.Farewell/Wave")));
+
+        let files = vec![dependency, file]
+            .into_iter()
+            .map(|file| (file.name().to_string(), file))
+            .collect();
+        assert_eq!(
+            missing_dependencies(&files),
+            vec!["other.proto".to_string()]
+        );
+    }
+
+    #[test]
+    fn should_load_file_descriptor_set() -> Result<(), Error> {
+        let metadata = Metadata::from_file_descriptor_set(include_bytes!(concat!(
+            env!("OUT_DIR"),
+            "/reflection_descriptor.bin"
+        )))?;
+        assert!(matches!(
+            metadata.get_query_type(PathAndQuery::from_static(
+                "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo"
+            )),
+            Ok(ConnectionType::Streaming)
+        ));
+        assert_err!(
+            Metadata::from_file_descriptor_set(b"not a descriptor set"),
+            Error::ProstDecodeError(_)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_load_proto_source() -> Result<(), Error> {
+        let metadata = Metadata::from_proto_source(
+            "greeter.proto",
+            "package helloworld;
+             service Greeter {
+                 rpc SayHello (HelloRequest) returns (HelloReply);
+                 rpc SayRepeatHello (HelloRequest) returns (stream HelloReply);
+             }",
+        )?;
+        assert!(matches!(
+            metadata.get_query_type(PathAndQuery::from_static("/helloworld.Greeter/SayHello")),
+            Ok(ConnectionType::Unary)
+        ));
+        assert!(matches!(
+            metadata.get_query_type(PathAndQuery::from_static(
+                "/helloworld.Greeter/SayRepeatHello"
+            )),
+            Ok(ConnectionType::ServerStreaming)
+        ));
+        assert_err!(
+            Metadata::from_proto_path("does/not/exist.proto"),
+            Error::IoError(_)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_reflect_either_version() -> Result<(), Error> {
+        for version in vec![ReflectionVersion::V1, ReflectionVersion::V1Alpha] {
+            let reflection = FakeReflection::new(vec![method("SayHello", false)]);
+            let dst = serve_reflection(version, reflection).await;
+            let metadata = Metadata::from_reflection_service(dst).await?;
+            assert_eq!(metadata.reflection_version(), Some(version));
+            assert_ok!(
+                metadata.get_query_type(PathAndQuery::from_static("/helloworld.Greeter/SayHello"))
+            );
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,96 @@
+use crate::Error;
+use bytes::Bytes;
+use hyper::http::{header::CONTENT_TYPE, HeaderValue};
+use hyper::Request as HttpRequest;
+
+pub const GRPC_CONTENT_TYPE: &str = "application/grpc";
+pub const GRPC_WEB_CONTENT_TYPE: &str = "application/grpc-web";
+pub const GRPC_WEB_CONTENT_TYPE_PROTO: &str = "application/grpc-web+proto";
+pub const GRPC_WEB_TEXT_CONTENT_TYPE: &str = "application/grpc-web-text";
+pub const GRPC_WEB_TEXT_CONTENT_TYPE_PROTO: &str = "application/grpc-web-text+proto";
+
+/// Wire format of a gRPC-Web body, selected by the request content type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// `application/grpc-web[+proto]`: frames are sent as raw bytes.
+    Binary,
+    /// `application/grpc-web-text[+proto]`: frames are base64 encoded.
+    Text,
+}
+
+impl Mode {
+    pub fn from_content_type(content_type: &HeaderValue) -> Option<Self> {
+        let content_type = content_type.to_str().ok()?.split(';').next()?.trim();
+        match content_type {
+            GRPC_WEB_CONTENT_TYPE | GRPC_WEB_CONTENT_TYPE_PROTO => Some(Mode::Binary),
+            GRPC_WEB_TEXT_CONTENT_TYPE | GRPC_WEB_TEXT_CONTENT_TYPE_PROTO => Some(Mode::Text),
+            _ => None,
+        }
+    }
+
+    pub fn from_request<B>(req: &HttpRequest<B>) -> Option<Self> {
+        req.headers()
+            .get(CONTENT_TYPE)
+            .and_then(Self::from_content_type)
+    }
+
+    /// Content type to answer with.
+    // TODO: may not be proto
+    pub fn content_type(&self) -> HeaderValue {
+        match self {
+            Mode::Binary => HeaderValue::from_static(GRPC_WEB_CONTENT_TYPE_PROTO),
+            Mode::Text => HeaderValue::from_static(GRPC_WEB_TEXT_CONTENT_TYPE_PROTO),
+        }
+    }
+
+    pub fn decode(&self, body: Bytes) -> Result<Vec<u8>, Error> {
+        match self {
+            Mode::Binary => Ok(body.to_vec()),
+            Mode::Text => Ok(base64::decode(body)?),
+        }
+    }
+
+    pub fn encode(&self, body: Vec<u8>) -> Vec<u8> {
+        match self {
+            Mode::Binary => body,
+            Mode::Text => base64::encode(body).into_bytes(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_get_mode_from_content_type() {
+        for (content_type, mode) in vec![
+            ("application/grpc-web", Some(Mode::Binary)),
+            ("application/grpc-web+proto", Some(Mode::Binary)),
+            ("application/grpc-web-text", Some(Mode::Text)),
+            (
+                "application/grpc-web-text+proto; charset=utf-8",
+                Some(Mode::Text),
+            ),
+            ("application/grpc", None),
+            ("application/json", None),
+        ] {
+            assert_eq!(
+                Mode::from_content_type(&HeaderValue::from_static(content_type)),
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn should_round_trip_body() -> Result<(), Error> {
+        let body = vec![0, 0, 0, 0, 7, 10, 5, 84, 111, 110, 105, 99];
+        for mode in vec![Mode::Binary, Mode::Text] {
+            let encoded = mode.encode(body.clone());
+            assert_eq!(mode.decode(encoded.into())?, body);
+        }
+        assert_eq!(Mode::Text.encode(body), b"AAAAAAcKBVRvbmlj".to_vec());
+
+        Ok(())
+    }
+}
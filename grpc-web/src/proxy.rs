@@ -0,0 +1,1128 @@
+use crate::balance::Balancer;
+use crate::channel::{connect, connect_lazy, unix_socket_path, UpstreamChannel};
+use crate::compression::Decompress;
+use crate::{
+    streaming_http_response, with_deadline, ConnectionType, Cors, Discovery, Error, GrpcRequest,
+    GrpcWebRequest, GrpcWebResponse, HeaderFilter, LoadBalancing, Metadata, MetadataStore, Mode,
+    ProxyCodec, ServicePattern, Timeouts, UpstreamTls, DEFAULT_DISCOVERY_INTERVAL,
+    DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MISS_REFRESH_INTERVAL, GRPC_ACCEPT_ENCODING_HEADER,
+    SUPPORTED_ENCODINGS,
+};
+use hyper::{
+    http::{header::ORIGIN, Method, StatusCode, Uri},
+    Body, Request as HttpRequest, Response as HttpResponse,
+};
+use std::convert::{Infallible, TryInto};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tonic::client::Grpc as GrpcClient;
+use tonic::codegen::StdError;
+use tonic::metadata::AsciiMetadataValue;
+use tonic::transport::{ClientTlsConfig, Endpoint};
+use tonic::Code;
+use tower::Service;
+
+/// Delays between attempts to discover a lazily connected upstream.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Configures and connects a [`GrpcWebProxy`].
+pub struct GrpcWebProxyBuilder {
+    cors: Cors,
+    header_filter: HeaderFilter,
+    timeouts: Timeouts,
+    max_message_size: usize,
+    metadata: Option<Metadata>,
+    refresh_interval: Option<Duration>,
+    miss_refresh_interval: Duration,
+    forward_unknown_methods: bool,
+    readiness_path: Option<String>,
+    /// Replicas of the default upstream.
+    replicas: Replicas,
+    routes: Vec<(ServicePattern, Replicas)>,
+    load_balancing: LoadBalancing,
+    health_check_interval: Option<Duration>,
+    discovery_interval: Duration,
+    tls: Option<UpstreamTls>,
+}
+
+impl Default for GrpcWebProxyBuilder {
+    fn default() -> Self {
+        Self {
+            cors: Cors::default(),
+            header_filter: HeaderFilter::default(),
+            timeouts: Timeouts::default(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            metadata: None,
+            refresh_interval: None,
+            miss_refresh_interval: DEFAULT_MISS_REFRESH_INTERVAL,
+            forward_unknown_methods: false,
+            readiness_path: None,
+            replicas: Replicas::default(),
+            routes: Vec::new(),
+            load_balancing: LoadBalancing::default(),
+            health_check_interval: None,
+            discovery_interval: DEFAULT_DISCOVERY_INTERVAL,
+            tls: None,
+        }
+    }
+}
+
+impl GrpcWebProxyBuilder {
+    pub fn cors(mut self, cors: Cors) -> Self {
+        self.cors = cors;
+        self
+    }
+
+    pub fn header_filter(mut self, header_filter: HeaderFilter) -> Self {
+        self.header_filter = header_filter;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Fail calls with `RESOURCE_EXHAUSTED` when a message of the client or
+    /// the upstream grows past `size` bytes once decompressed, rather than
+    /// [`DEFAULT_MAX_MESSAGE_SIZE`].
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Describe the upstream methods, rather than asking its reflection
+    /// service.
+    pub fn metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Reflect the upstream again every `interval`, so that new methods
+    /// are found without a restart. A zero `interval` disables refreshes.
+    pub fn refresh_interval(mut self, interval: Duration) -> Self {
+        self.refresh_interval = Some(interval).filter(|interval| !interval.is_zero());
+        self
+    }
+
+    /// Shortest time between refreshes caused by calls to unknown methods.
+    pub fn miss_refresh_interval(mut self, interval: Duration) -> Self {
+        self.miss_refresh_interval = interval;
+        self
+    }
+
+    /// Forward calls to methods missing from the metadata as server
+    /// streaming calls, which unary calls are indistinguishable from on the
+    /// gRPC-Web wire. The upstream then needs neither reflection nor
+    /// descriptors.
+    pub fn forward_unknown_methods(mut self, forward: bool) -> Self {
+        self.forward_unknown_methods = forward;
+        self
+    }
+
+    /// Answer `GET` requests for `path` with `200 OK` once the upstream
+    /// services are known, and `503 Service Unavailable` until then.
+    pub fn readiness_path(mut self, path: impl Into<String>) -> Self {
+        self.readiness_path = Some(path.into());
+        self
+    }
+
+    /// Send calls to services matching `pattern` to the upstream at `dst`,
+    /// which has its own connection and metadata. Routes are tried in the
+    /// order they were added, before the default upstream. Adding the same
+    /// pattern again adds a replica to its upstream.
+    pub fn route(mut self, pattern: impl Into<ServicePattern>, dst: Uri) -> Self {
+        self.route_replicas(pattern.into()).uris.push(dst);
+        self
+    }
+
+    /// Send calls to services matching `pattern` to the replicas found by
+    /// `discovery`, as well as any added with [`route`](Self::route).
+    pub fn route_discovery(
+        mut self,
+        pattern: impl Into<ServicePattern>,
+        discovery: impl Discovery,
+    ) -> Self {
+        self.route_replicas(pattern.into())
+            .discoveries
+            .push(Arc::new(discovery));
+        self
+    }
+
+    fn route_replicas(&mut self, pattern: ServicePattern) -> &mut Replicas {
+        let index = match self.routes.iter().position(|(other, _)| *other == pattern) {
+            Some(index) => index,
+            None => {
+                self.routes.push((pattern, Replicas::default()));
+                self.routes.len() - 1
+            }
+        };
+        &mut self.routes[index].1
+    }
+
+    /// Balance calls to the default upstream across another replica at
+    /// `dst`, which must serve the same methods.
+    pub fn replica(mut self, dst: Uri) -> Self {
+        self.replicas.uris.push(dst);
+        self
+    }
+
+    /// Balance calls to the default upstream across the replicas found by
+    /// `discovery` as well.
+    pub fn discovery(mut self, discovery: impl Discovery) -> Self {
+        self.replicas.discoveries.push(Arc::new(discovery));
+        self
+    }
+
+    /// Time between two discoveries of the replicas of each upstream.
+    /// Replicas which are no longer found stop receiving new calls, while
+    /// calls in flight carry on. Must not be zero.
+    pub fn discovery_interval(mut self, interval: Duration) -> Self {
+        self.discovery_interval = interval;
+        self
+    }
+
+    /// How calls are spread across the replicas of each upstream.
+    pub fn load_balancing(mut self, load_balancing: LoadBalancing) -> Self {
+        self.load_balancing = load_balancing;
+        self
+    }
+
+    /// Call `grpc.health.v1.Health/Check` on every replica each
+    /// `interval`, taking replicas out of rotation while they are not
+    /// serving. Replicas without a health service stay in rotation. A zero
+    /// `interval` disables health checks.
+    pub fn health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = Some(interval).filter(|interval| !interval.is_zero());
+        self
+    }
+
+    /// Connect to every upstream over TLS, both to forward calls and to
+    /// reflect its services.
+    pub fn tls(mut self, tls: UpstreamTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Connect to the default upstream gRPC server at `dst`, and to the
+    /// upstream of every route.
+    pub async fn connect<D>(mut self, dst: D) -> Result<GrpcWebProxy, Error>
+    where
+        D: TryInto<Endpoint>,
+        D::Error: Into<StdError>,
+    {
+        let dst = Endpoint::new(dst)?.uri().clone();
+        self.replicas.uris.insert(0, dst);
+        let replicas = std::mem::take(&mut self.replicas);
+        let upstream = self.connect_upstream(replicas).await?;
+        let mut routes = Vec::new();
+        for (pattern, replicas) in std::mem::take(&mut self.routes) {
+            let upstream = self.connect_upstream(replicas).await?;
+            routes.push(Route { pattern, upstream });
+        }
+        Ok(self.finish(upstream, routes))
+    }
+
+    /// Create the proxy without waiting for the upstream gRPC servers. Their
+    /// services are discovered in the background, retrying with exponential
+    /// backoff, and calls fail with `UNAVAILABLE` until then.
+    pub fn connect_lazy<D>(mut self, dst: D) -> Result<GrpcWebProxy, Error>
+    where
+        D: TryInto<Endpoint>,
+        D::Error: Into<StdError>,
+    {
+        let dst = Endpoint::new(dst)?.uri().clone();
+        self.replicas.uris.insert(0, dst);
+        self.build()
+    }
+
+    /// Create the proxy for the default upstream replicas added with
+    /// [`replica`](Self::replica) and [`discovery`](Self::discovery),
+    /// without waiting for them, like [`connect_lazy`](Self::connect_lazy).
+    pub fn build(mut self) -> Result<GrpcWebProxy, Error> {
+        if self.replicas.uris.is_empty() && self.replicas.discoveries.is_empty() {
+            return Err(Error::InvalidEndpoints(
+                "no replicas for the default upstream".to_string(),
+            ));
+        }
+        let replicas = std::mem::take(&mut self.replicas);
+        let upstream = self.connect_upstream_lazy(replicas)?;
+        let routes = std::mem::take(&mut self.routes)
+            .into_iter()
+            .map(|(pattern, replicas)| {
+                let upstream = self.connect_upstream_lazy(replicas)?;
+                Ok(Route { pattern, upstream })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(self.finish(upstream, routes))
+    }
+
+    async fn connect_upstream(&self, mut replicas: Replicas) -> Result<Upstream, Error> {
+        self.check_discovery_interval(&replicas)?;
+        replicas.tls = self.client_tls_config()?;
+        let endpoints = replicas.discover().await?;
+        let metadata = match &self.metadata {
+            Some(metadata) => MetadataStore::new(metadata.clone()),
+            None => match MetadataStore::from_reflection_service(endpoints.clone()).await {
+                Err(err) if self.forward_unknown_methods => {
+                    log::warn!(
+                        "Unable to reflect {}, forwarding every method: {}",
+                        replicas.describe(),
+                        err
+                    );
+                    MetadataStore::with_endpoints(Metadata::default(), endpoints.clone())
+                }
+                metadata => metadata?,
+            },
+        };
+        let mut channels = Vec::new();
+        for endpoint in endpoints {
+            channels.push((endpoint.uri().clone(), connect(&endpoint).await?));
+        }
+        Ok(self.upstream(channels, metadata, replicas))
+    }
+
+    /// Only the replicas given up front are known at first, the rest are
+    /// discovered in the background.
+    fn connect_upstream_lazy(&self, mut replicas: Replicas) -> Result<Upstream, Error> {
+        self.check_discovery_interval(&replicas)?;
+        replicas.tls = self.client_tls_config()?;
+        let endpoints = replicas
+            .uris
+            .iter()
+            .map(|uri| replicas.endpoint(uri.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let channels = endpoints
+            .iter()
+            .map(|endpoint| Ok((endpoint.uri().clone(), connect_lazy(endpoint)?)))
+            .collect::<Result<_, Error>>()?;
+        let metadata = match &self.metadata {
+            Some(metadata) => MetadataStore::new(metadata.clone()),
+            None => {
+                let name = replicas.describe();
+                let metadata = MetadataStore::pending(endpoints);
+                tokio::spawn(discover(
+                    metadata.clone(),
+                    name,
+                    self.forward_unknown_methods,
+                ));
+                metadata
+            }
+        };
+        Ok(self.upstream(channels, metadata, replicas))
+    }
+
+    fn client_tls_config(&self) -> Result<Option<ClientTlsConfig>, Error> {
+        self.tls
+            .as_ref()
+            .map(UpstreamTls::client_tls_config)
+            .transpose()
+    }
+
+    fn check_discovery_interval(&self, replicas: &Replicas) -> Result<(), Error> {
+        if !replicas.discoveries.is_empty() && self.discovery_interval.is_zero() {
+            return Err(Error::InvalidEndpoints(
+                "zero discovery interval".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn upstream(
+        &self,
+        channels: Vec<(Uri, UpstreamChannel)>,
+        metadata: MetadataStore,
+        replicas: Replicas,
+    ) -> Upstream {
+        let metadata = metadata.miss_refresh_interval(self.miss_refresh_interval);
+        if let Some(interval) = self.refresh_interval {
+            metadata.refresh_every(interval);
+        }
+        let balancer = Arc::new(Balancer::new(channels, self.load_balancing));
+        if let Some(interval) = self.health_check_interval {
+            balancer.health_check_every(interval);
+        }
+        if !replicas.discoveries.is_empty() {
+            tokio::spawn(rediscover(
+                Arc::downgrade(&balancer),
+                metadata.clone(),
+                replicas,
+                self.discovery_interval,
+            ));
+        }
+        Upstream { balancer, metadata }
+    }
+
+    fn finish(self, upstream: Upstream, routes: Vec<Route>) -> GrpcWebProxy {
+        GrpcWebProxy {
+            upstream,
+            routes: Arc::new(routes),
+            cors: self.cors,
+            header_filter: self.header_filter,
+            timeouts: self.timeouts,
+            max_message_size: self.max_message_size,
+            forward_unknown_methods: self.forward_unknown_methods,
+            readiness_path: self.readiness_path,
+        }
+    }
+}
+
+/// Replicas of an upstream, given up front or found through discovery.
+#[derive(Clone, Default)]
+struct Replicas {
+    uris: Vec<Uri>,
+    discoveries: Vec<Arc<dyn Discovery>>,
+    tls: Option<ClientTlsConfig>,
+}
+
+impl Replicas {
+    /// Every replica currently known.
+    async fn discover(&self) -> Result<Vec<Endpoint>, Error> {
+        let mut uris = self.uris.clone();
+        for discovery in &self.discoveries {
+            uris.extend(discovery.discover().await?);
+        }
+        uris.into_iter().map(|uri| self.endpoint(uri)).collect()
+    }
+
+    fn endpoint(&self, uri: Uri) -> Result<Endpoint, Error> {
+        match &self.tls {
+            Some(_) if unix_socket_path(&uri).is_some() => Err(Error::InvalidTlsConfig(format!(
+                "TLS is not supported over unix sockets: {}",
+                uri
+            ))),
+            Some(tls) => Ok(Endpoint::from(uri).tls_config(tls.clone())?),
+            None => Ok(Endpoint::from(uri)),
+        }
+    }
+
+    /// Addresses of the replicas given up front, for logging.
+    fn describe(&self) -> String {
+        if self.uris.is_empty() {
+            return "the discovered upstream".to_string();
+        }
+        self.uris
+            .iter()
+            .map(|uri| uri.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Discover the replicas of an upstream every `interval`, until its
+/// balancer has been dropped. The current replicas are kept when discovery
+/// fails.
+async fn rediscover(
+    balancer: Weak<Balancer>,
+    metadata: MetadataStore,
+    replicas: Replicas,
+    interval: Duration,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let balancer = match balancer.upgrade() {
+            Some(balancer) => balancer,
+            None => return,
+        };
+        let endpoints = match replicas.discover().await {
+            Ok(endpoints) => endpoints,
+            Err(err) => {
+                log::warn!("Unable to discover replicas: {}", err);
+                continue;
+            }
+        };
+        if let Err(err) = balancer.update(&endpoints) {
+            log::warn!("Unable to update replicas: {}", err);
+            continue;
+        }
+        metadata.set_endpoints(endpoints);
+    }
+}
+
+/// Reflect the upstream called `name` until it succeeds, backing off
+/// exponentially.
+async fn discover(metadata: MetadataStore, name: String, forward_unknown_methods: bool) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match metadata.refresh().await {
+            Ok(()) => return,
+            Err(Error::Status(status))
+                if forward_unknown_methods && status.code() == Code::Unimplemented =>
+            {
+                log::warn!(
+                    "{} has no reflection service, forwarding every method",
+                    name
+                );
+                metadata.set_ready();
+                return;
+            }
+            Err(err) => {
+                log::warn!(
+                    "Unable to reflect {}, retrying in {:?}: {}",
+                    name,
+                    backoff,
+                    err
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Replicas of an upstream gRPC server, along with the services they were
+/// found to have.
+#[derive(Clone)]
+struct Upstream {
+    balancer: Arc<Balancer>,
+    metadata: MetadataStore,
+}
+
+struct Route {
+    pattern: ServicePattern,
+    upstream: Upstream,
+}
+
+/// Forwards gRPC-Web requests to an upstream gRPC server, e.g.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), rust_grpc_web::Error> {
+/// let proxy = rust_grpc_web::GrpcWebProxy::builder()
+///     .connect("http://[::1]:50052")
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct GrpcWebProxy {
+    /// Upstream for services without a route.
+    upstream: Upstream,
+    routes: Arc<Vec<Route>>,
+    cors: Cors,
+    header_filter: HeaderFilter,
+    timeouts: Timeouts,
+    max_message_size: usize,
+    forward_unknown_methods: bool,
+    readiness_path: Option<String>,
+}
+
+impl GrpcWebProxy {
+    pub fn builder() -> GrpcWebProxyBuilder {
+        GrpcWebProxyBuilder::default()
+    }
+
+    /// Every upstream, starting with the default.
+    fn upstreams(&self) -> impl Iterator<Item = &Upstream> {
+        std::iter::once(&self.upstream).chain(self.routes.iter().map(|route| &route.upstream))
+    }
+
+    /// Upstream serving the method at `path`.
+    fn upstream_for(&self, path: &str) -> &Upstream {
+        let service = ServicePattern::service_of(path).unwrap_or_default();
+        self.routes
+            .iter()
+            .find(|route| route.pattern.matches(service))
+            .map_or(&self.upstream, |route| &route.upstream)
+    }
+
+    /// Methods currently known to the proxy, across every upstream.
+    pub fn metadata(&self) -> Arc<Metadata> {
+        // NOTE: routes take precedence over the default upstream, and
+        // earlier routes over later ones, as they do in `upstream_for`
+        let metadata = self.routes.iter().rev().fold(
+            self.upstream.metadata.load().as_ref().clone(),
+            |metadata, route| metadata.merge(route.upstream.metadata.load().as_ref().clone()),
+        );
+        Arc::new(metadata)
+    }
+
+    /// Reflect every upstream again, e.g. after deploying new methods.
+    pub async fn refresh_metadata(&self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for upstream in self.upstreams() {
+            if let Err(err) = upstream.metadata.refresh().await {
+                log::warn!("Unable to refresh metadata: {}", err);
+                result = result.and(Err(err));
+            }
+        }
+        result
+    }
+
+    /// Whether the services of every upstream are known, so that calls can
+    /// be forwarded.
+    pub fn is_ready(&self) -> bool {
+        self.upstreams()
+            .all(|upstream| upstream.metadata.is_ready())
+    }
+
+    /// Wait until the services of every upstream are known.
+    pub async fn ready(&self) {
+        for upstream in self.upstreams() {
+            upstream.metadata.ready().await;
+        }
+    }
+
+    async fn forward_http_request(
+        &mut self,
+        http_request: HttpRequest<Body>,
+        mode: Mode,
+    ) -> Result<HttpResponse<Body>, Error> {
+        let path = http_request
+            .uri()
+            .path_and_query()
+            .ok_or(Error::InvalidRequest)?
+            .to_owned();
+        let Upstream { balancer, metadata } = self.upstream_for(path.path()).clone();
+        if !metadata.is_ready() {
+            return Err(Error::NotReady);
+        }
+        let codec = ProxyCodec::default();
+
+        log::info!("Forwarding http request: {:?}", http_request);
+        let grpc_web_request =
+            GrpcWebRequest::from_http_request_with_limit(http_request, mode, self.max_message_size)
+                .await?;
+        let encoding = grpc_web_request.accept_encoding();
+        let mut grpc_request: GrpcRequest = grpc_web_request.try_into()?;
+        let deadline = self
+            .timeouts
+            .apply(path.path(), grpc_request.metadata_mut())?;
+        self.header_filter.apply(grpc_request.metadata_mut());
+        // NOTE: the request message is sent uncompressed, which every
+        // upstream accepts, while responses may use any supported encoding
+        grpc_request.metadata_mut().insert(
+            GRPC_ACCEPT_ENCODING_HEADER,
+            AsciiMetadataValue::from_static(SUPPORTED_ENCODINGS),
+        );
+
+        let connection_type = match metadata.load().get_query_type(path.clone()) {
+            Err(Error::UnknownService) | Err(Error::UnknownMethod)
+                if metadata.refresh_on_miss().await =>
+            {
+                metadata.load().get_query_type(path.clone())
+            }
+            connection_type => connection_type,
+        };
+        let connection_type = match connection_type {
+            Err(Error::UnknownService) | Err(Error::UnknownMethod)
+                if self.forward_unknown_methods =>
+            {
+                log::debug!("Forwarding unknown method {}", path);
+                ConnectionType::ServerStreaming
+            }
+            connection_type => connection_type?,
+        };
+        if let ConnectionType::ClientStreaming | ConnectionType::Streaming = connection_type {
+            // NOTE: client-side and bi-directional streaming are not
+            // currently supported by the gRPC-Web protocol
+            return Err(Error::Unsupported);
+        }
+
+        // NOTE: the call counts as in flight until its response body ends
+        let replica = balancer.pick()?;
+        let mut client = GrpcClient::new(Decompress::new(replica.channel(), self.max_message_size));
+        client.ready().await?;
+
+        // NOTE: `Grpc::unary` merges the trailers into the response
+        // metadata, so unary calls also read their message from a stream
+        let grpc_response =
+            with_deadline(deadline, client.server_streaming(grpc_request, path, codec)).await;
+        let http_response = match (grpc_response, connection_type) {
+            (Ok(grpc_response), ConnectionType::Unary) => {
+                GrpcWebResponse::from_grpc_response(grpc_response, encoding, deadline)
+                    .await?
+                    .into_http_response(mode)
+            }
+            (Ok(grpc_response), _) => {
+                streaming_http_response(grpc_response, mode, encoding, deadline)
+                    .map(|body| replica.hold_until_end(body))
+            }
+            (Err(status), _) => {
+                log::debug!("Upstream returned {:?}", status);
+                GrpcWebResponse::from(status).into_http_response(mode)
+            }
+        };
+
+        Ok(http_response)
+    }
+
+    pub async fn handle_http_request(
+        &mut self,
+        http_request: HttpRequest<Body>,
+    ) -> HttpResponse<Body> {
+        if http_request.method() == Method::GET
+            && Some(http_request.uri().path()) == self.readiness_path.as_deref()
+        {
+            let mut http_response = HttpResponse::new(Body::empty());
+            if !self.is_ready() {
+                *http_response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            }
+            return http_response;
+        }
+
+        if Cors::is_preflight(&http_request) {
+            return self.cors.preflight_response(&http_request);
+        }
+
+        let origin = http_request.headers().get(ORIGIN).cloned();
+        let mode = Mode::from_request(&http_request);
+        let result = match (http_request.method(), mode) {
+            (&Method::OPTIONS, _) => Ok(HttpResponse::new(Body::empty())),
+            (&Method::POST, Some(mode)) => self.forward_http_request(http_request, mode).await,
+            _ => Err(Error::InvalidRequest),
+        };
+
+        let mut http_response = result.unwrap_or_else(|err| {
+            log::warn!("{:?}", err);
+            err.into_http_response(mode.unwrap_or(Mode::Text))
+        });
+        self.cors.add_headers(origin.as_ref(), &mut http_response);
+        http_response
+    }
+}
+
+impl Service<HttpRequest<Body>> for GrpcWebProxy {
+    type Response = HttpResponse<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // NOTE: upstream readiness is awaited per request, so that errors
+        // are answered as gRPC-Web responses
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, http_request: HttpRequest<Body>) -> Self::Future {
+        let mut proxy = self.clone();
+        Box::pin(async move { Ok(proxy.handle_http_request(http_request).await) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::COMPRESSED_FLAG;
+    use crate::test_util::temp_path;
+    use crate::{
+        Encoding, FileDiscovery, FRAME_HEADER_SIZE, GRPC_ENCODING_HEADER, GRPC_TIMEOUT_HEADER,
+    };
+    use hyper::http::{header::CONTENT_TYPE, uri::PathAndQuery, HeaderMap, HeaderValue};
+    use hyper::service::make_service_fn;
+    use std::net::SocketAddr;
+
+    /// Upstream without reflection, which echoes the request message and
+    /// `grpc-timeout`, and names itself in the `x-upstream` header. The
+    /// message is gzip compressed if the request accepts it.
+    async fn echo(
+        grpc_request: HttpRequest<Body>,
+        name: &'static str,
+    ) -> Result<HttpResponse<Body>, Infallible> {
+        if grpc_request.uri().path().starts_with("/grpc.reflection.") {
+            let mut http_response = HttpResponse::new(Body::empty());
+            http_response
+                .headers_mut()
+                .insert("grpc-status", HeaderValue::from_static("12"));
+            return Ok(http_response);
+        }
+
+        let timeout = grpc_request.headers().get(GRPC_TIMEOUT_HEADER).cloned();
+        let encoding = match grpc_request.headers().get(GRPC_ACCEPT_ENCODING_HEADER) {
+            Some(value) if value.to_str().unwrap().contains("gzip") => Encoding::Gzip,
+            _ => Encoding::Identity,
+        };
+        let mut message = hyper::body::to_bytes(grpc_request.into_body())
+            .await
+            .unwrap()
+            .to_vec();
+        if encoding == Encoding::Gzip {
+            let compressed = encoding.compress(&message[FRAME_HEADER_SIZE..]).unwrap();
+            message = vec![COMPRESSED_FLAG, 0, 0, 0, compressed.len() as u8];
+            message.extend(compressed);
+        }
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data(message.into()).await?;
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            sender.send_trailers(trailers).await
+        });
+        let mut http_response = HttpResponse::new(body);
+        http_response
+            .headers_mut()
+            .insert("x-upstream", HeaderValue::from_static(name));
+        http_response.headers_mut().insert(
+            GRPC_ENCODING_HEADER,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        if let Some(timeout) = timeout {
+            http_response
+                .headers_mut()
+                .insert("x-grpc-timeout", timeout);
+        }
+        Ok(http_response)
+    }
+
+    async fn serve(addr: SocketAddr, name: &'static str) -> String {
+        let make_svc = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(tower::service_fn(move |grpc_request| {
+                echo(grpc_request, name)
+            }))
+        });
+        let server = hyper::Server::bind(&addr).http2_only(true).serve(make_svc);
+        let dst = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        dst
+    }
+
+    /// Upstream like [`serve`], listening on the unix socket at `path`.
+    #[cfg(unix)]
+    fn serve_unix(path: &std::path::Path, name: &'static str) {
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(
+                    hyper::server::conn::Http::new()
+                        .http2_only(true)
+                        .serve_connection(
+                            stream,
+                            tower::service_fn(move |grpc_request| echo(grpc_request, name)),
+                        ),
+                );
+            }
+        });
+    }
+
+    fn grpc_web_request(path: &str) -> HttpRequest<Body> {
+        HttpRequest::builder()
+            .method(Method::POST)
+            .uri(path)
+            .header(CONTENT_TYPE, "application/grpc-web-text")
+            .body(Body::from("AAAAAAcKBVRvbmlj"))
+            .unwrap()
+    }
+
+    fn readiness_request() -> HttpRequest<Body> {
+        HttpRequest::builder()
+            .method(Method::GET)
+            .uri("/ready")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_forward_unknown_methods() -> Result<(), Error> {
+        let dst = serve(([127, 0, 0, 1], 0).into(), "default").await;
+        assert!(GrpcWebProxy::builder().connect(dst.clone()).await.is_err());
+
+        let mut proxy = GrpcWebProxy::builder()
+            .forward_unknown_methods(true)
+            .connect(dst)
+            .await?;
+        let http_response = proxy
+            .handle_http_request(grpc_web_request("/helloworld.Greeter/SayHello"))
+            .await;
+        assert_eq!(
+            hyper::body::to_bytes(http_response.into_body()).await?,
+            "AAAAAAcKBVRvbmlj\
+             gAAAAA9ncnBjLXN0YXR1czowDQo="
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_decompress_upstream_messages() -> Result<(), Error> {
+        let dst = serve(([127, 0, 0, 1], 0).into(), "default").await;
+        let mut proxy = GrpcWebProxy::builder()
+            .forward_unknown_methods(true)
+            .max_message_size(6)
+            .connect(dst)
+            .await?;
+
+        // NOTE: the request fits within the allowance for base64 and frame
+        // headers, but the echoed message is 7 bytes once decompressed
+        let http_response = proxy
+            .handle_http_request(grpc_web_request("/helloworld.Greeter/SayHello"))
+            .await;
+        let body = hyper::body::to_bytes(http_response.into_body()).await?;
+        let body = base64::decode(&body).unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("grpc-status:8\r\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_forward_timeout_through_header_filter() -> Result<(), Error> {
+        let dst = serve(([127, 0, 0, 1], 0).into(), "default").await;
+        let mut proxy = GrpcWebProxy::builder()
+            .forward_unknown_methods(true)
+            .header_filter(HeaderFilter::new(vec!["authorization".to_string()], vec![]))
+            .connect(dst)
+            .await?;
+
+        let mut http_request = grpc_web_request("/helloworld.Greeter/SayHello");
+        http_request
+            .headers_mut()
+            .insert(GRPC_TIMEOUT_HEADER, HeaderValue::from_static("1S"));
+        let http_response = proxy.handle_http_request(http_request).await;
+        assert_eq!(http_response.headers()["x-grpc-timeout"], "1000000u");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_connect_lazily() -> Result<(), Error> {
+        // NOTE: reserve a port for the upstream to start on later
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let mut proxy = GrpcWebProxy::builder()
+            .forward_unknown_methods(true)
+            .readiness_path("/ready")
+            .connect_lazy(format!("http://{}", addr))?;
+
+        assert!(!proxy.is_ready());
+        let http_response = proxy.handle_http_request(readiness_request()).await;
+        assert_eq!(http_response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let http_response = proxy
+            .handle_http_request(grpc_web_request("/helloworld.Greeter/SayHello"))
+            .await;
+        assert_eq!(http_response.headers()["grpc-status"], "14");
+
+        serve(addr, "default").await;
+        tokio::time::timeout(Duration::from_secs(5), proxy.ready())
+            .await
+            .expect("upstream was not discovered");
+        let http_response = proxy.handle_http_request(readiness_request()).await;
+        assert_eq!(http_response.status(), StatusCode::OK);
+        let http_response = proxy
+            .handle_http_request(grpc_web_request("/helloworld.Greeter/SayHello"))
+            .await;
+        assert_eq!(
+            hyper::body::to_bytes(http_response.into_body()).await?,
+            "AAAAAAcKBVRvbmlj\
+             gAAAAA9ncnBjLXN0YXR1czowDQo="
+        );
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn should_reject_unknown_methods_without_upstream() -> Result<(), Error> {
+        let metadata = Metadata::from_proto_source(
+            "greeter.proto",
+            "package helloworld;
+             service Greeter {
+                 rpc SayHello (HelloRequest) returns (HelloReply);
+             }",
+        )?;
+        // NOTE: nothing listens on the socket, so the replica is never ready
+        let mut proxy = GrpcWebProxy::builder()
+            .metadata(metadata)
+            .replica(crate::unix_socket_uri(
+                temp_path("down.sock").to_str().unwrap(),
+            )?)
+            .build()?;
+
+        let http_response = proxy
+            .handle_http_request(grpc_web_request("/helloworld.Greeter/SayGoodbye"))
+            .await;
+        assert_eq!(http_response.headers()["grpc-status"], "12");
+        let http_response = proxy
+            .handle_http_request(grpc_web_request("/helloworld.Greeter/SayHello"))
+            .await;
+        assert_eq!(http_response.headers()["grpc-status"], "14");
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn should_connect_to_unix_socket() -> Result<(), Error> {
+        let path = temp_path("upstream.sock");
+        let mut proxy = GrpcWebProxy::builder()
+            .forward_unknown_methods(true)
+            .replica(crate::unix_socket_uri(path.to_str().unwrap())?)
+            .build()?;
+
+        let http_response = proxy
+            .handle_http_request(grpc_web_request("/helloworld.Greeter/SayHello"))
+            .await;
+        assert_eq!(http_response.headers()["grpc-status"], "14");
+
+        serve_unix(&path, "default");
+        tokio::time::timeout(Duration::from_secs(5), proxy.ready())
+            .await
+            .expect("upstream was not discovered");
+        let http_response = proxy
+            .handle_http_request(grpc_web_request("/helloworld.Greeter/SayHello"))
+            .await;
+        assert_eq!(http_response.headers()["x-upstream"], "default");
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_reject_tls_over_unix_sockets() -> Result<(), Error> {
+        let result = GrpcWebProxy::builder()
+            .tls(UpstreamTls::new())
+            .replica(crate::unix_socket_uri("/run/greeter.sock")?)
+            .build();
+        assert!(matches!(result, Err(Error::InvalidTlsConfig(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_route_services() -> Result<(), Error> {
+        let default = serve(([127, 0, 0, 1], 0).into(), "default").await;
+        let greeter = serve(([127, 0, 0, 1], 0).into(), "greeter").await;
+        let mut proxy = GrpcWebProxy::builder()
+            .forward_unknown_methods(true)
+            .route("helloworld.*", greeter.parse().unwrap())
+            .connect(default)
+            .await?;
+
+        for (path, upstream) in vec![
+            ("/helloworld.Greeter/SayHello", "greeter"),
+            ("/helloworld.v2.Greeter/SayHello", "greeter"),
+            ("/other.Greeter/SayHello", "default"),
+        ] {
+            let http_response = proxy.handle_http_request(grpc_web_request(path)).await;
+            assert_eq!(http_response.headers()["x-upstream"], upstream);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_prefer_routes_in_metadata() -> Result<(), Error> {
+        let greeter = |returns: &str| {
+            Metadata::from_proto_source(
+                "greeter.proto",
+                &format!(
+                    "package helloworld;
+                     service Greeter {{
+                         rpc SayHello (HelloRequest) returns ({});
+                     }}",
+                    returns
+                ),
+            )
+        };
+        let mut proxy = GrpcWebProxy::builder()
+            .metadata(greeter("HelloReply")?)
+            .connect_lazy("http://127.0.0.1:1")?;
+        let upstream = |metadata| Upstream {
+            balancer: proxy.upstream.balancer.clone(),
+            metadata: MetadataStore::new(metadata),
+        };
+        proxy.routes = Arc::new(vec![
+            Route {
+                pattern: "helloworld.*".into(),
+                upstream: upstream(greeter("stream HelloReply")?),
+            },
+            Route {
+                pattern: "helloworld.*".into(),
+                upstream: upstream(greeter("HelloReply")?),
+            },
+        ]);
+
+        assert!(matches!(
+            proxy
+                .metadata()
+                .get_query_type(PathAndQuery::from_static("/helloworld.Greeter/SayHello")),
+            Ok(ConnectionType::ServerStreaming)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_reject_zero_discovery_interval() {
+        let result = GrpcWebProxy::builder()
+            .discovery(FileDiscovery::new(temp_path("replicas.json")))
+            .discovery_interval(Duration::from_secs(0))
+            .build();
+        assert!(matches!(result, Err(Error::InvalidEndpoints(_))));
+    }
+
+    #[tokio::test]
+    async fn should_discover_replicas() -> Result<(), Error> {
+        let first = serve(([127, 0, 0, 1], 0).into(), "first").await;
+        let second = serve(([127, 0, 0, 1], 0).into(), "second").await;
+        let path = temp_path("replicas.json");
+        let write = |dst: &str| std::fs::write(&path, format!(r#"{{"endpoints": ["{}"]}}"#, dst));
+
+        write(&first)?;
+        let mut proxy = GrpcWebProxy::builder()
+            .forward_unknown_methods(true)
+            .discovery(FileDiscovery::new(&path))
+            .discovery_interval(Duration::from_millis(10))
+            .build()?;
+        tokio::time::timeout(Duration::from_secs(5), proxy.ready())
+            .await
+            .expect("upstream was not discovered");
+        let http_response = proxy
+            .handle_http_request(grpc_web_request("/helloworld.Greeter/SayHello"))
+            .await;
+        assert_eq!(http_response.headers()["x-upstream"], "first");
+
+        write(&second)?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let http_response = proxy
+                    .handle_http_request(grpc_web_request("/helloworld.Greeter/SayHello"))
+                    .await;
+                if http_response.headers()["x-upstream"] == "second" {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("replica was not replaced");
+
+        // NOTE: no replicas at all is taken as a failed discovery
+        std::fs::write(&path, r#"{"endpoints": []}"#)?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let http_response = proxy
+            .handle_http_request(grpc_web_request("/helloworld.Greeter/SayHello"))
+            .await;
+        assert_eq!(http_response.headers()["x-upstream"], "second");
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_balance_replicas() -> Result<(), Error> {
+        let first = serve(([127, 0, 0, 1], 0).into(), "first").await;
+        let second = serve(([127, 0, 0, 1], 0).into(), "second").await;
+        let greeter = serve(([127, 0, 0, 1], 0).into(), "greeter").await;
+        let mut proxy = GrpcWebProxy::builder()
+            .forward_unknown_methods(true)
+            .replica(second.parse().unwrap())
+            .route("helloworld.*", greeter.parse().unwrap())
+            .route("helloworld.*", first.parse().unwrap())
+            .connect(first)
+            .await?;
+
+        for (path, upstream) in vec![
+            ("/other.Greeter/SayHello", "first"),
+            ("/other.Greeter/SayHello", "second"),
+            ("/other.Greeter/SayHello", "first"),
+            ("/helloworld.Greeter/SayHello", "greeter"),
+            ("/helloworld.Greeter/SayHello", "first"),
+        ] {
+            let http_response = proxy.handle_http_request(grpc_web_request(path)).await;
+            assert_eq!(http_response.headers()["x-upstream"], upstream);
+        }
+
+        Ok(())
+    }
+}
@@ -1,17 +1,173 @@
-use crate::Error;
+use crate::{headers_to_metadata, Encoding, Error, Mode, DEFAULT_MAX_MESSAGE_SIZE};
+use byteorder::{BigEndian, ByteOrder};
+use hyper::body::HttpBody;
 use hyper::{Body, Request as HttpRequest};
 use std::convert::TryInto;
+use tonic::metadata::MetadataMap;
 use tonic::Request;
 
 pub type GrpcRequest = Request<Vec<u8>>;
 
+/// Size of the flags byte plus the big-endian message length.
+pub const FRAME_HEADER_SIZE: usize = 5;
+
+pub(crate) const COMPRESSED_FLAG: u8 = 1;
+pub(crate) const TRAILER_FLAG: u8 = 1 << 7;
+
+/// A single length-prefixed message of a gRPC-Web body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub flags: u8,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn is_compressed(&self) -> bool {
+        self.flags & COMPRESSED_FLAG != 0
+    }
+
+    pub fn is_trailer(&self) -> bool {
+        self.flags & TRAILER_FLAG != 0
+    }
+}
+
+/// Split a decoded body into its frames, rejecting unknown flags,
+/// truncated frames and trailing bytes.
+pub fn decode_frames(mut buf: &[u8]) -> Result<Vec<Frame>, Error> {
+    let mut frames = Vec::new();
+    while !buf.is_empty() {
+        if buf.len() < FRAME_HEADER_SIZE {
+            return Err(Error::TruncatedFrame);
+        }
+
+        let flags = buf[0];
+        if flags & !(COMPRESSED_FLAG | TRAILER_FLAG) != 0 {
+            return Err(Error::InvalidFrameFlags(flags));
+        }
+
+        let len: usize = BigEndian::read_u32(&buf[1..FRAME_HEADER_SIZE]).try_into()?;
+        let end = FRAME_HEADER_SIZE
+            .checked_add(len)
+            .ok_or(Error::TruncatedFrame)?;
+        let data = buf
+            .get(FRAME_HEADER_SIZE..end)
+            .ok_or(Error::TruncatedFrame)?;
+
+        frames.push(Frame {
+            flags,
+            data: data.to_vec(),
+        });
+        buf = &buf[end..];
+    }
+    Ok(frames)
+}
+
+/// Take the first complete frame, header included, off the front of
+/// `buf`.
+pub(crate) fn split_frame(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    if buf.len() < FRAME_HEADER_SIZE {
+        return None;
+    }
+    let len = BigEndian::read_u32(&buf[1..FRAME_HEADER_SIZE]) as usize;
+    if buf.len() < FRAME_HEADER_SIZE + len {
+        return None;
+    }
+    Some(buf.drain(..FRAME_HEADER_SIZE + len).collect())
+}
+
+/// Read and decode a request body, failing rather than buffering more than
+/// a frame holding a message of `max_message_size` bytes takes in `mode`.
+pub(crate) async fn read_body(
+    mut body: Body,
+    mode: Mode,
+    max_message_size: usize,
+) -> Result<Vec<u8>, Error> {
+    let limit = FRAME_HEADER_SIZE.saturating_add(max_message_size);
+    let limit = match mode {
+        Mode::Binary => limit,
+        // NOTE: base64 encodes every 3 bytes as 4 characters
+        Mode::Text => (limit / 3 + 1).saturating_mul(4),
+    };
+    let too_large = || Error::MessageTooLarge(max_message_size);
+    if body.size_hint().lower() > limit as u64 {
+        return Err(too_large());
+    }
+
+    let mut buf = Vec::new();
+    while let Some(data) = body.data().await {
+        let data = data?;
+        if buf.len() + data.len() > limit {
+            return Err(too_large());
+        }
+        buf.extend_from_slice(&data);
+    }
+    mode.decode(buf.into())
+}
+
+/// Decode the messages of a request body, decompressing them with
+/// `encoding` where flagged, up to `max_size` bytes each.
+pub(crate) fn decode_messages(
+    body: &[u8],
+    encoding: Encoding,
+    max_size: usize,
+) -> Result<Vec<Vec<u8>>, Error> {
+    decode_frames(body)?
+        .into_iter()
+        .map(
+            |frame| match (frame.is_trailer(), frame.is_compressed(), encoding) {
+                (true, _, _) => Err(Error::InvalidRequest),
+                (false, false, _) => Ok(frame.data),
+                (false, true, Encoding::Identity) => Err(Error::InvalidFrameFlags(frame.flags)),
+                (false, true, encoding) => encoding.decompress(&frame.data, max_size),
+            },
+        )
+        .collect()
+}
+
 #[derive(Debug)]
-pub struct GrpcWebRequest(Vec<u8>);
+pub struct GrpcWebRequest {
+    metadata: MetadataMap,
+    body: Vec<u8>,
+    encoding: Encoding,
+    accept_encoding: Encoding,
+    max_message_size: usize,
+}
 
 impl GrpcWebRequest {
-    pub async fn from_http_request(mut req: HttpRequest<Body>) -> Result<Self, Error> {
-        let body = hyper::body::to_bytes(req.body_mut()).await?;
-        Ok(Self(base64::decode(body)?))
+    pub async fn from_http_request(req: HttpRequest<Body>, mode: Mode) -> Result<Self, Error> {
+        Self::from_http_request_with_limit(req, mode, DEFAULT_MAX_MESSAGE_SIZE).await
+    }
+
+    /// Reject messages larger than `max_message_size` bytes, either as sent
+    /// or once decompressed, rather than [`DEFAULT_MAX_MESSAGE_SIZE`].
+    pub async fn from_http_request_with_limit(
+        req: HttpRequest<Body>,
+        mode: Mode,
+        max_message_size: usize,
+    ) -> Result<Self, Error> {
+        let metadata = headers_to_metadata(req.headers());
+        let encoding = Encoding::from_encoding_header(req.headers())?;
+        let accept_encoding = Encoding::from_accept_encoding_header(req.headers());
+        Ok(Self {
+            metadata,
+            body: read_body(req.into_body(), mode, max_message_size).await?,
+            encoding,
+            accept_encoding,
+            max_message_size,
+        })
+    }
+
+    pub fn metadata(&self) -> &MetadataMap {
+        &self.metadata
+    }
+
+    /// Encoding to compress the response messages with.
+    pub fn accept_encoding(&self) -> Encoding {
+        self.accept_encoding
+    }
+
+    pub fn frames(&self) -> Result<Vec<Frame>, Error> {
+        decode_frames(&self.body)
     }
 }
 
@@ -19,9 +175,15 @@ impl TryInto<GrpcRequest> for GrpcWebRequest {
     type Error = Error;
 
     fn try_into(self) -> Result<GrpcRequest, Self::Error> {
-        Ok(GrpcRequest::new(
-            self.0.get(5..).ok_or(Error::InvalidRequest)?.to_vec(),
-        ))
+        // NOTE: unary and server-streaming calls carry exactly one message
+        let mut messages = decode_messages(&self.body, self.encoding, self.max_message_size)?;
+        if messages.len() != 1 {
+            return Err(Error::InvalidRequest);
+        }
+
+        let mut grpc_request = GrpcRequest::new(messages.remove(0));
+        *grpc_request.metadata_mut() = self.metadata;
+        Ok(grpc_request)
     }
 }
 
@@ -29,14 +191,172 @@ impl TryInto<GrpcRequest> for GrpcWebRequest {
 mod tests {
     use super::*;
 
+    fn grpc_web_request(body: Vec<u8>) -> GrpcWebRequest {
+        GrpcWebRequest {
+            metadata: MetadataMap::new(),
+            body,
+            encoding: Encoding::Identity,
+            accept_encoding: Encoding::Identity,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+
     #[tokio::test]
     async fn should_decode_http_request() -> Result<(), Error> {
         let http_request = HttpRequest::<Body>::new(b"AAAAAAcKBVRvbmlj".to_vec().into());
         assert_eq!(
-            GrpcWebRequest::from_http_request(http_request).await?.0,
+            GrpcWebRequest::from_http_request(http_request, Mode::Text)
+                .await?
+                .body,
+            vec![0, 0, 0, 0, 7, 10, 5, 84, 111, 110, 105, 99]
+        );
+
+        let http_request = HttpRequest::builder()
+            .header("x-tenant-id", "tenant")
+            .body(Body::from(vec![
+                0, 0, 0, 0, 7, 10, 5, 84, 111, 110, 105, 99,
+            ]))
+            .unwrap();
+        let grpc_web_request =
+            GrpcWebRequest::from_http_request(http_request, Mode::Binary).await?;
+        assert_eq!(
+            grpc_web_request.body,
             vec![0, 0, 0, 0, 7, 10, 5, 84, 111, 110, 105, 99]
         );
 
+        let grpc_request: GrpcRequest = grpc_web_request.try_into()?;
+        assert_eq!(
+            grpc_request.metadata().get("x-tenant-id").unwrap(),
+            "tenant"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_limit_body_size() -> Result<(), Error> {
+        let message = vec![0, 0, 0, 0, 7, 10, 5, 84, 111, 110, 105, 99];
+        assert_eq!(
+            read_body(message.clone().into(), Mode::Binary, 7).await?,
+            message
+        );
+        assert_eq!(
+            read_body(base64::encode(&message).into(), Mode::Text, 7).await?,
+            message
+        );
+        assert!(matches!(
+            read_body(message.clone().into(), Mode::Binary, 6).await,
+            Err(Error::MessageTooLarge(6))
+        ));
+
+        // NOTE: without a length, the body is only read up to the limit
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move { while sender.send_data(vec![0; 1024].into()).await.is_ok() {} });
+        assert!(matches!(
+            read_body(body, Mode::Binary, 4096).await,
+            Err(Error::MessageTooLarge(4096))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_decode_frames() -> Result<(), Error> {
+        assert_eq!(decode_frames(&[])?, vec![]);
+        assert_eq!(
+            decode_frames(&[0, 0, 0, 0, 2, 10, 5, 128, 0, 0, 0, 1, 97])?,
+            vec![
+                Frame {
+                    flags: 0,
+                    data: vec![10, 5]
+                },
+                Frame {
+                    flags: 128,
+                    data: vec![97]
+                },
+            ]
+        );
+        assert_eq!(
+            decode_frames(&[0, 0, 0, 0, 0])?,
+            vec![Frame {
+                flags: 0,
+                data: vec![]
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_malformed_frames() {
+        assert!(matches!(
+            decode_frames(&[0, 0, 0]),
+            Err(Error::TruncatedFrame)
+        ));
+        assert!(matches!(
+            decode_frames(&[0, 0, 0, 0, 7, 10, 5]),
+            Err(Error::TruncatedFrame)
+        ));
+        assert!(matches!(
+            decode_frames(&[0, 0, 0, 0, 1, 10, 5]),
+            Err(Error::TruncatedFrame)
+        ));
+        assert!(matches!(
+            decode_frames(&[2, 0, 0, 0, 1, 10]),
+            Err(Error::InvalidFrameFlags(2))
+        ));
+    }
+
+    #[test]
+    fn should_convert_to_grpc_request() -> Result<(), Error> {
+        let grpc_request: GrpcRequest =
+            grpc_web_request(vec![0, 0, 0, 0, 7, 10, 5, 84, 111, 110, 105, 99]).try_into()?;
+        assert_eq!(
+            grpc_request.into_inner(),
+            vec![10, 5, 84, 111, 110, 105, 99]
+        );
+
+        let result: Result<GrpcRequest, _> = grpc_web_request(vec![]).try_into();
+        assert!(matches!(result, Err(Error::InvalidRequest)));
+
+        let result: Result<GrpcRequest, _> =
+            grpc_web_request(vec![0, 0, 0, 0, 1, 10, 0, 0, 0, 0, 1, 10]).try_into();
+        assert!(matches!(result, Err(Error::InvalidRequest)));
+
+        let result: Result<GrpcRequest, _> = grpc_web_request(vec![1, 0, 0, 0, 1, 10]).try_into();
+        assert!(matches!(result, Err(Error::InvalidFrameFlags(1))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_decompress_grpc_request() -> Result<(), Error> {
+        let message = Encoding::Gzip.compress(&[10, 5, 84, 111, 110, 105, 99])?;
+        let mut body = vec![1, 0, 0, 0, message.len() as u8];
+        body.extend(message);
+
+        let mut request = grpc_web_request(body);
+        request.encoding = Encoding::Gzip;
+        let grpc_request: GrpcRequest = request.try_into()?;
+        assert_eq!(
+            grpc_request.into_inner(),
+            vec![10, 5, 84, 111, 110, 105, 99]
+        );
+
+        let mut request = grpc_web_request(vec![1, 0, 0, 0, 1, 10]);
+        request.encoding = Encoding::Gzip;
+        let result: Result<GrpcRequest, _> = request.try_into();
+        assert!(matches!(result, Err(Error::IoError(_))));
+
+        let message = Encoding::Gzip.compress(&[0; 64])?;
+        let mut body = vec![1, 0, 0, 0, message.len() as u8];
+        body.extend(message);
+        let mut request = grpc_web_request(body);
+        request.encoding = Encoding::Gzip;
+        request.max_message_size = 63;
+        let result: Result<GrpcRequest, _> = request.try_into();
+        assert!(matches!(result, Err(Error::MessageTooLarge(63))));
+
         Ok(())
     }
 }
@@ -1,24 +1,70 @@
-use crate::Error;
+use crate::request::COMPRESSED_FLAG;
+use crate::{
+    with_deadline, Encoding, Error, Mode, GRPC_ACCEPT_ENCODING_HEADER, GRPC_ENCODING_HEADER,
+    SUPPORTED_ENCODINGS,
+};
 use byteorder::{BigEndian, ByteOrder};
-use hyper::{Body, Response as HttpResponse};
+use futures::stream::{self, Stream};
+use hyper::{
+    http::{header::CONTENT_TYPE, HeaderMap, HeaderValue},
+    Body, Response as HttpResponse,
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
 use std::convert::{TryFrom, TryInto};
+use tokio::time::Instant;
 use tonic::metadata::MetadataMap;
+use tonic::{Status, Streaming};
 
+/// Upstream response, read message by message up to its trailers.
+pub type GrpcStreamingResponse = tonic::Response<Streaming<Vec<u8>>>;
+
+/// Upstream response with its single message already read, and no trailers
+/// other than its metadata.
+#[deprecated(note = "use `GrpcStreamingResponse`, which keeps the trailers of the call")]
 pub type GrpcResponse = tonic::Response<Vec<u8>>;
 
-pub struct GrpcWebResponse(Vec<u8>);
+/// Headers which describe the upstream HTTP/2 transport rather than the
+/// call, and so are not copied from the initial metadata.
+const TRANSPORT_HEADERS: &[&str] = &[
+    "content-type",
+    "content-length",
+    "connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "grpc-encoding",
+    "grpc-accept-encoding",
+];
+
+const GRPC_STATUS_HEADER: &str = "grpc-status";
+const GRPC_MESSAGE_HEADER: &str = "grpc-message";
+const GRPC_STATUS_DETAILS_HEADER: &str = "grpc-status-details-bin";
+
+/// Bytes which must be percent-encoded in `grpc-message`, see
+/// https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-HTTP2.md#responses
+const GRPC_MESSAGE_ENCODE_SET: &AsciiSet = &CONTROLS.add(b'%');
+
+pub struct GrpcWebResponse {
+    metadata: MetadataMap,
+    body: Vec<u8>,
+    encoding: Encoding,
+}
 
 impl TryFrom<(Vec<u8>, MetadataMap)> for GrpcWebResponse {
     type Error = Error;
 
-    fn try_from((body, metadata): (Vec<u8>, MetadataMap)) -> Result<Self, Self::Error> {
-        let body = copy_trailers_to_payload(body)?;
-        let mut body = base64::encode(body);
-        body.push_str(&base64::encode(&extract_headers(metadata)?));
-        Ok(Self(body.into_bytes()))
+    fn try_from((body, trailers): (Vec<u8>, MetadataMap)) -> Result<Self, Self::Error> {
+        let mut body = copy_trailers_to_payload(body)?;
+        body.extend(extract_headers(trailers)?);
+        Ok(Self {
+            metadata: MetadataMap::new(),
+            body,
+            encoding: Encoding::Identity,
+        })
     }
 }
 
+#[allow(deprecated)]
 impl TryFrom<GrpcResponse> for GrpcWebResponse {
     type Error = Error;
 
@@ -29,16 +75,157 @@ impl TryFrom<GrpcResponse> for GrpcWebResponse {
     }
 }
 
-impl Into<Vec<u8>> for GrpcWebResponse {
-    fn into(self) -> Vec<u8> {
-        self.0
+/// Trailers-only response, with the status sent as HTTP headers.
+impl From<Status> for GrpcWebResponse {
+    fn from(status: Status) -> Self {
+        Self {
+            metadata: status_to_metadata(&status),
+            body: Vec::new(),
+            encoding: Encoding::Identity,
+        }
+    }
+}
+
+impl GrpcWebResponse {
+    /// Buffer every message of the upstream response along with the
+    /// trailers it ends with, or `DEADLINE_EXCEEDED` once the deadline
+    /// passes. Messages are compressed with `encoding`.
+    pub async fn from_grpc_response(
+        grpc_response: GrpcStreamingResponse,
+        encoding: Encoding,
+        deadline: Option<Instant>,
+    ) -> Result<Self, Error> {
+        let metadata = grpc_response.metadata().clone();
+        let mut streaming = grpc_response.into_inner();
+
+        let mut body = Vec::new();
+        let trailers = loop {
+            match with_deadline(deadline, streaming.message()).await {
+                Ok(Some(message)) => body.extend(encode_message(message, encoding)?),
+                Ok(None) => break read_trailers(&mut streaming, deadline).await,
+                Err(status) => break status_to_metadata(&status),
+            }
+        };
+        body.extend(extract_headers(trailers)?);
+
+        Ok(Self {
+            metadata,
+            body,
+            encoding,
+        })
+    }
+
+    pub fn encode(self, mode: Mode) -> Vec<u8> {
+        mode.encode(self.body)
+    }
+
+    pub fn into_http_response(self, mode: Mode) -> HttpResponse<Body> {
+        let Self {
+            metadata,
+            body,
+            encoding,
+        } = self;
+        let mut http_response = HttpResponse::new(mode.encode(body).into());
+        add_response_headers(http_response.headers_mut(), metadata, mode, encoding);
+        http_response
+    }
+}
+
+/// Answer with the initial metadata as soon as the upstream does and
+/// forward its messages as they arrive.
+pub fn streaming_http_response(
+    grpc_response: GrpcStreamingResponse,
+    mode: Mode,
+    encoding: Encoding,
+    deadline: Option<Instant>,
+) -> HttpResponse<Body> {
+    let metadata = grpc_response.metadata().clone();
+    let streaming = grpc_response.into_inner();
+
+    let mut http_response = HttpResponse::new(Body::wrap_stream(encode_streaming(
+        streaming, mode, encoding, deadline,
+    )));
+    add_response_headers(http_response.headers_mut(), metadata, mode, encoding);
+    http_response
+}
+
+pub(crate) fn add_response_headers(
+    header_map: &mut HeaderMap,
+    metadata: MetadataMap,
+    mode: Mode,
+    encoding: Encoding,
+) {
+    let mut headers = metadata.into_headers();
+    for name in TRANSPORT_HEADERS {
+        headers.remove(*name);
+    }
+    header_map.extend(headers);
+    header_map.insert(CONTENT_TYPE, mode.content_type());
+    header_map.insert(
+        GRPC_ACCEPT_ENCODING_HEADER,
+        HeaderValue::from_static(SUPPORTED_ENCODINGS),
+    );
+    if encoding != Encoding::Identity {
+        header_map.insert(
+            GRPC_ENCODING_HEADER,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+    }
+}
+
+/// Encode a server stream as one data frame per message, followed by a
+/// single trailer frame once the upstream trailers have been received or
+/// the deadline passes.
+pub fn encode_streaming(
+    streaming: Streaming<Vec<u8>>,
+    mode: Mode,
+    encoding: Encoding,
+    deadline: Option<Instant>,
+) -> impl Stream<Item = Result<Vec<u8>, Error>> + Send + 'static {
+    stream::unfold(Some(streaming), move |streaming| async move {
+        let mut streaming = streaming?;
+        let trailers = match with_deadline(deadline, streaming.message()).await {
+            Ok(Some(message)) => {
+                let frame = encode_message(message, encoding);
+                return Some((frame.map(|frame| mode.encode(frame)), Some(streaming)));
+            }
+            Ok(None) => read_trailers(&mut streaming, deadline).await,
+            Err(status) => status_to_metadata(&status),
+        };
+        let frame = extract_headers(trailers);
+        Some((frame.map(|frame| mode.encode(frame)), None))
+    })
+}
+
+async fn read_trailers(
+    streaming: &mut Streaming<Vec<u8>>,
+    deadline: Option<Instant>,
+) -> MetadataMap {
+    match with_deadline(deadline, streaming.trailers()).await {
+        Ok(trailers) => trailers.unwrap_or_default(),
+        Err(status) => status_to_metadata(&status),
     }
 }
 
-impl Into<HttpResponse<Body>> for GrpcWebResponse {
-    fn into(self) -> HttpResponse<Body> {
-        HttpResponse::new(self.0.into())
+/// Encode a status the way a gRPC server would send it in its trailers,
+/// along with any custom metadata attached to it.
+pub fn status_to_metadata(status: &Status) -> MetadataMap {
+    let mut headers = status.metadata().clone().into_headers();
+    headers.insert(GRPC_STATUS_HEADER, HeaderValue::from(status.code() as i32));
+
+    let message = utf8_percent_encode(status.message(), GRPC_MESSAGE_ENCODE_SET).to_string();
+    if let Ok(message) = HeaderValue::from_str(&message) {
+        headers.insert(GRPC_MESSAGE_HEADER, message);
     }
+
+    if !status.details().is_empty() {
+        let details = base64::encode_config(status.details(), base64::STANDARD_NO_PAD);
+        if let Ok(details) = HeaderValue::from_str(&details) {
+            headers.insert(GRPC_STATUS_DETAILS_HEADER, details);
+        }
+    }
+
+    MetadataMap::from_headers(headers)
 }
 
 fn copy_trailers_to_payload(body: Vec<u8>) -> Result<Vec<u8>, Error> {
@@ -47,13 +234,27 @@ fn copy_trailers_to_payload(body: Vec<u8>) -> Result<Vec<u8>, Error> {
     Ok([&trailer[..], &body[..]].concat())
 }
 
-fn extract_headers(meta: MetadataMap) -> Result<Vec<u8>, Error> {
+/// Frame a message, compressing it if that makes it any smaller. The
+/// flag is set per frame, so small messages are sent as they are.
+pub(crate) fn encode_message(message: Vec<u8>, encoding: Encoding) -> Result<Vec<u8>, Error> {
+    if encoding != Encoding::Identity {
+        let compressed = encoding.compress(&message)?;
+        if compressed.len() < message.len() {
+            let mut frame = copy_trailers_to_payload(compressed)?;
+            frame[0] |= COMPRESSED_FLAG;
+            return Ok(frame);
+        }
+    }
+    copy_trailers_to_payload(message)
+}
+
+pub(crate) fn extract_headers(meta: MetadataMap) -> Result<Vec<u8>, Error> {
     let headers = meta.into_headers();
 
     let body: Vec<u8> = headers
-        .into_iter()
+        .iter()
         .filter_map(|(key, value)| {
-            Some(format!("{}:{}\r\n", key?, value.to_str().ok()?).into_bytes())
+            Some(format!("{}:{}\r\n", key, value.to_str().ok()?).into_bytes())
         })
         .flatten()
         .collect();
@@ -66,7 +267,11 @@ fn extract_headers(meta: MetadataMap) -> Result<Vec<u8>, Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::codec::ProxyDecoder;
+    use crate::{DEFAULT_MAX_MESSAGE_SIZE, FRAME_HEADER_SIZE};
+    use futures::stream::StreamExt;
     use tonic::metadata::AsciiMetadataValue;
+    use tonic::Code;
 
     #[test]
     fn should_copy_trailers_to_payload() -> Result<(), Error> {
@@ -78,6 +283,25 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn should_compress_messages() -> Result<(), Error> {
+        assert_eq!(
+            encode_message(vec![10, 1, 97], Encoding::Gzip)?,
+            vec![0, 0, 0, 0, 3, 10, 1, 97]
+        );
+
+        let message = vec![97; 1024];
+        let frame = encode_message(message.clone(), Encoding::Gzip)?;
+        assert_eq!(frame[0], COMPRESSED_FLAG);
+        assert!(frame.len() < message.len());
+        assert_eq!(
+            Encoding::Gzip.decompress(&frame[FRAME_HEADER_SIZE..], DEFAULT_MAX_MESSAGE_SIZE)?,
+            message
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn should_extract_headers() -> Result<(), Error> {
         // "content-type": "application/grpc", "date": "Mon, 12 Oct 2020 08:32:05 GMT", "grpc-status": "0"
@@ -100,4 +324,161 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn should_encode_streaming() -> Result<(), Error> {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data(vec![0, 0, 0, 0, 1, 7].into()).await?;
+            sender.send_data(vec![0, 0, 0, 0, 1, 8].into()).await?;
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            sender.send_trailers(trailers).await
+        });
+
+        let streaming = Streaming::new_request(ProxyDecoder, body);
+        let frames = encode_streaming(streaming, Mode::Binary, Encoding::Identity, None)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(
+            frames,
+            vec![
+                vec![0, 0, 0, 0, 1, 7],
+                vec![0, 0, 0, 0, 1, 8],
+                vec![
+                    128, 0, 0, 0, 15, 103, 114, 112, 99, 45, 115, 116, 97, 116, 117, 115, 58, 48,
+                    13, 10
+                ],
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_end_stream_at_deadline() -> Result<(), Error> {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data(vec![0, 0, 0, 0, 1, 7].into()).await?;
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            sender.send_data(vec![0, 0, 0, 0, 1, 8].into()).await
+        });
+
+        let streaming = Streaming::new_request(ProxyDecoder, body);
+        let deadline = Instant::now() + std::time::Duration::from_millis(50);
+        let frames = encode_streaming(streaming, Mode::Binary, Encoding::Identity, Some(deadline))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], vec![0, 0, 0, 0, 1, 7]);
+        assert_eq!(frames[1][0], 128);
+        assert!(String::from_utf8_lossy(&frames[1][5..]).starts_with("grpc-status:4\r\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_buffer_grpc_response() -> Result<(), Error> {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data(vec![0, 0, 0, 0, 1, 7].into()).await?;
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            sender.send_trailers(trailers).await
+        });
+
+        let mut grpc_response =
+            GrpcStreamingResponse::new(Streaming::new_request(ProxyDecoder, body));
+        grpc_response
+            .metadata_mut()
+            .insert("x-custom", AsciiMetadataValue::from_str("a").unwrap());
+
+        let http_response =
+            GrpcWebResponse::from_grpc_response(grpc_response, Encoding::Identity, None)
+                .await?
+                .into_http_response(Mode::Binary);
+        assert_eq!(http_response.headers()["x-custom"], "a");
+        assert_eq!(
+            hyper::body::to_bytes(http_response.into_body()).await?,
+            vec![
+                0, 0, 0, 0, 1, 7, 128, 0, 0, 0, 15, 103, 114, 112, 99, 45, 115, 116, 97, 116, 117,
+                115, 58, 48, 13, 10
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_extract_repeated_headers() -> Result<(), Error> {
+        let mut meta = MetadataMap::new();
+        meta.append("x-custom", AsciiMetadataValue::from_str("a").unwrap());
+        meta.append("x-custom", AsciiMetadataValue::from_str("b").unwrap());
+
+        assert_eq!(
+            &extract_headers(meta)?[5..],
+            &b"x-custom:a\r\nx-custom:b\r\n"[..]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_send_metadata_as_headers() {
+        let mut meta = MetadataMap::new();
+        meta.append(
+            "content-type",
+            AsciiMetadataValue::from_str("application/grpc").unwrap(),
+        );
+        meta.append("x-custom", AsciiMetadataValue::from_str("a").unwrap());
+
+        let mut headers = HeaderMap::new();
+        add_response_headers(&mut headers, meta.clone(), Mode::Text, Encoding::Identity);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers["x-custom"], "a");
+        assert_eq!(headers[CONTENT_TYPE], "application/grpc-web-text+proto");
+        assert_eq!(headers["grpc-accept-encoding"], SUPPORTED_ENCODINGS);
+
+        let mut headers = HeaderMap::new();
+        add_response_headers(&mut headers, meta, Mode::Text, Encoding::Gzip);
+        assert_eq!(headers["grpc-encoding"], "gzip");
+    }
+
+    #[test]
+    fn should_convert_status_to_metadata() {
+        let mut status = Status::with_details(
+            Code::NotFound,
+            "100% missing\nüber",
+            vec![1, 2, 3, 4].into(),
+        );
+        status
+            .metadata_mut()
+            .insert("x-custom", AsciiMetadataValue::from_str("a").unwrap());
+
+        let headers = status_to_metadata(&status).into_headers();
+        assert_eq!(headers["grpc-status"], "5");
+        assert_eq!(headers["grpc-message"], "100%25 missing%0A%C3%BCber");
+        assert_eq!(headers["grpc-status-details-bin"], "AQIDBA");
+        assert_eq!(headers["x-custom"], "a");
+    }
+
+    #[tokio::test]
+    async fn should_send_trailers_only_response() -> Result<(), Error> {
+        let http_response = GrpcWebResponse::from(Status::unavailable("unavailable"))
+            .into_http_response(Mode::Text);
+        assert_eq!(http_response.status(), 200);
+        assert_eq!(http_response.headers()["grpc-status"], "14");
+        assert_eq!(http_response.headers()["grpc-message"], "unavailable");
+        assert!(hyper::body::to_bytes(http_response.into_body())
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,80 @@
+/// Fully-qualified service names routed to an upstream, e.g.
+/// `helloworld.Greeter`, or a pattern with a single `*` wildcard such as
+/// `helloworld.*` or `*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServicePattern(String);
+
+impl ServicePattern {
+    pub fn new(pattern: impl Into<String>) -> Self {
+        Self(pattern.into())
+    }
+
+    pub fn matches(&self, service: &str) -> bool {
+        wildcard_match(&self.0, service)
+    }
+
+    /// The service called by a request path such as
+    /// `/helloworld.Greeter/SayHello`.
+    pub(crate) fn service_of(path: &str) -> Option<&str> {
+        path.split('/').nth(1).filter(|service| !service.is_empty())
+    }
+}
+
+impl From<&str> for ServicePattern {
+    fn from(pattern: &str) -> Self {
+        Self::new(pattern)
+    }
+}
+
+impl From<String> for ServicePattern {
+    fn from(pattern: String) -> Self {
+        Self::new(pattern)
+    }
+}
+
+/// Whether `value` is `pattern`, or matches its single `*` wildcard.
+pub(crate) fn wildcard_match(pattern: &str, value: &str) -> bool {
+    match pattern.find('*') {
+        Some(index) => {
+            let (prefix, suffix) = (&pattern[..index], &pattern[index + 1..]);
+            value.len() >= prefix.len() + suffix.len()
+                && value.starts_with(prefix)
+                && value.ends_with(suffix)
+        }
+        None => value == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_match_services() {
+        let pattern = ServicePattern::new("helloworld.Greeter");
+        assert!(pattern.matches("helloworld.Greeter"));
+        assert!(!pattern.matches("helloworld.Greeter2"));
+
+        let pattern = ServicePattern::new("helloworld.*");
+        assert!(pattern.matches("helloworld.Greeter"));
+        assert!(pattern.matches("helloworld.v1.Farewell"));
+        assert!(!pattern.matches("helloworld"));
+        assert!(!pattern.matches("other.Greeter"));
+
+        let pattern = ServicePattern::new("*.Health");
+        assert!(pattern.matches("grpc.health.v1.Health"));
+        assert!(!pattern.matches("grpc.health.v1.Healthy"));
+
+        assert!(ServicePattern::new("*").matches("anything.At.All"));
+    }
+
+    #[test]
+    fn should_find_service_of_path() {
+        assert_eq!(
+            ServicePattern::service_of("/helloworld.Greeter/SayHello"),
+            Some("helloworld.Greeter")
+        );
+        assert_eq!(ServicePattern::service_of("/"), None);
+        assert_eq!(ServicePattern::service_of(""), None);
+    }
+}
@@ -0,0 +1,412 @@
+use crate::request::{decode_messages, read_body, split_frame, COMPRESSED_FLAG};
+use crate::response::{add_response_headers, encode_message, extract_headers};
+use crate::{
+    status_to_metadata, Cors, Encoding, Error, Mode, DEFAULT_MAX_MESSAGE_SIZE, FRAME_HEADER_SIZE,
+    GRPC_ACCEPT_ENCODING_HEADER, GRPC_CONTENT_TYPE, GRPC_ENCODING_HEADER,
+};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+use futures::stream::{self, Stream};
+use hyper::body::HttpBody;
+use hyper::http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE, ORIGIN, TE},
+    HeaderValue, Method,
+};
+use hyper::{Body, Request as HttpRequest, Response as HttpResponse};
+use std::convert::TryInto;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::StdError;
+use tonic::metadata::MetadataMap;
+use tonic::Status;
+use tower::{Layer, Service};
+
+/// Serves gRPC-Web from a gRPC service in the same process, e.g.
+/// `GrpcWebLayer::new().cors(Cors::default()).layer(GreeterServer::new(greeter))`.
+#[derive(Debug, Clone)]
+pub struct GrpcWebLayer {
+    cors: Option<Cors>,
+    max_message_size: usize,
+}
+
+impl Default for GrpcWebLayer {
+    fn default() -> Self {
+        Self {
+            cors: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+impl GrpcWebLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer preflight requests and add the CORS headers to gRPC-Web
+    /// responses, so that pages on other origins can call the service.
+    pub fn cors(mut self, cors: Cors) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+
+    /// Reject request messages which decompress to more than `size` bytes,
+    /// rather than [`DEFAULT_MAX_MESSAGE_SIZE`].
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+}
+
+impl<S> Layer<S> for GrpcWebLayer {
+    type Service = GrpcWebService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcWebService {
+            inner,
+            cors: self.cors.clone(),
+            max_message_size: self.max_message_size,
+        }
+    }
+}
+
+/// Translates gRPC-Web requests into gRPC requests for the inner service,
+/// and its responses back. Any other request is passed through, so native
+/// gRPC clients can share the same server.
+#[derive(Debug, Clone)]
+pub struct GrpcWebService<S> {
+    inner: S,
+    cors: Option<Cors>,
+    max_message_size: usize,
+}
+
+impl<S> GrpcWebService<S> {
+    /// Without CORS, and with the default maximum message size, see
+    /// [`GrpcWebLayer`] to change either.
+    pub fn new(inner: S) -> Self {
+        GrpcWebLayer::default().layer(inner)
+    }
+}
+
+impl<S, B> Service<HttpRequest<Body>> for GrpcWebService<S>
+where
+    S: Service<HttpRequest<Body>, Response = HttpResponse<B>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: HttpBody<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<StdError>,
+{
+    type Response = HttpResponse<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, http_request: HttpRequest<Body>) -> Self::Future {
+        // NOTE: call the instance that was driven to readiness
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        if let Some(cors) = &self.cors {
+            if Cors::is_preflight(&http_request) {
+                let http_response = cors.preflight_response(&http_request);
+                return Box::pin(async move { Ok(http_response.map(BoxBody::map_from)) });
+            }
+        }
+
+        let mode = match (http_request.method(), Mode::from_request(&http_request)) {
+            (&Method::POST, Some(mode)) => mode,
+            _ => {
+                let future = inner.call(http_request);
+                return Box::pin(async move { Ok(future.await?.map(BoxBody::map_from)) });
+            }
+        };
+
+        let cors = self.cors.clone();
+        let max_message_size = self.max_message_size;
+        Box::pin(async move {
+            let origin = http_request.headers().get(ORIGIN).cloned();
+            let mut http_response =
+                match into_grpc_request(http_request, mode, max_message_size).await {
+                    Ok((grpc_request, encoding)) => {
+                        let grpc_response = inner.call(grpc_request).await?;
+                        into_grpc_web_response(grpc_response, mode, encoding)
+                    }
+                    Err(err) => {
+                        log::warn!("{:?}", err);
+                        err.into_http_response(mode)
+                    }
+                };
+            if let Some(cors) = cors {
+                cors.add_headers(origin.as_ref(), &mut http_response);
+            }
+            Ok(http_response.map(BoxBody::map_from))
+        })
+    }
+}
+
+/// Decode a gRPC-Web request into a gRPC request with uncompressed
+/// messages, along with the encoding to compress the response with.
+async fn into_grpc_request(
+    http_request: HttpRequest<Body>,
+    mode: Mode,
+    max_message_size: usize,
+) -> Result<(HttpRequest<Body>, Encoding), Error> {
+    let (mut parts, body) = http_request.into_parts();
+    let encoding = Encoding::from_encoding_header(&parts.headers)?;
+    let accept_encoding = Encoding::from_accept_encoding_header(&parts.headers);
+
+    let body = read_body(body, mode, max_message_size).await?;
+    let mut grpc_body = Vec::with_capacity(body.len());
+    for message in decode_messages(&body, encoding, max_message_size)? {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        BigEndian::write_u32(&mut header[1..], message.len().try_into()?);
+        grpc_body.extend_from_slice(&header);
+        grpc_body.extend(message);
+    }
+
+    let headers = &mut parts.headers;
+    headers.remove(CONTENT_LENGTH);
+    headers.remove(GRPC_ENCODING_HEADER);
+    headers.remove(GRPC_ACCEPT_ENCODING_HEADER);
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(GRPC_CONTENT_TYPE));
+    headers.insert(TE, HeaderValue::from_static("trailers"));
+
+    Ok((
+        HttpRequest::from_parts(parts, grpc_body.into()),
+        accept_encoding,
+    ))
+}
+
+fn into_grpc_web_response<B>(
+    grpc_response: HttpResponse<B>,
+    mode: Mode,
+    encoding: Encoding,
+) -> HttpResponse<Body>
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<StdError>,
+{
+    let (parts, body) = grpc_response.into_parts();
+    let mut http_response = HttpResponse::new(Body::wrap_stream(encode_body(body, mode, encoding)));
+    *http_response.status_mut() = parts.status;
+    add_response_headers(
+        http_response.headers_mut(),
+        MetadataMap::from_headers(parts.headers),
+        mode,
+        encoding,
+    );
+    http_response
+}
+
+/// Re-frame a gRPC response body, sending its trailers as the final
+/// frame. A trailers-only response has no body, so nothing is sent.
+fn encode_body<B>(
+    body: B,
+    mode: Mode,
+    encoding: Encoding,
+) -> impl Stream<Item = Result<Vec<u8>, Error>> + Send + 'static
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<StdError>,
+{
+    stream::unfold(
+        Some((Box::pin(body), Vec::new())),
+        move |state| async move {
+            let (mut body, mut buf) = state?;
+            loop {
+                if let Some(frame) = split_frame(&mut buf) {
+                    let frame = if frame[0] & COMPRESSED_FLAG != 0 {
+                        Ok(frame)
+                    } else {
+                        encode_message(frame[FRAME_HEADER_SIZE..].to_vec(), encoding)
+                    };
+                    return Some((frame.map(|frame| mode.encode(frame)), Some((body, buf))));
+                }
+
+                match body.data().await {
+                    Some(Ok(data)) => buf.extend_from_slice(&data),
+                    Some(Err(err)) => {
+                        let frame = extract_headers(status_to_metadata(&into_status(err)));
+                        return Some((frame.map(|frame| mode.encode(frame)), None));
+                    }
+                    None => break,
+                }
+            }
+
+            let trailers = if !buf.is_empty() {
+                status_to_metadata(&Status::internal("Truncated frame"))
+            } else {
+                match body.trailers().await {
+                    Ok(trailers) => MetadataMap::from_headers(trailers?),
+                    Err(err) => status_to_metadata(&into_status(err)),
+                }
+            };
+            let frame = extract_headers(trailers);
+            Some((frame.map(|frame| mode.encode(frame)), None))
+        },
+    )
+}
+
+fn into_status(err: impl Into<StdError>) -> Status {
+    match err.into().downcast::<Status>() {
+        Ok(status) => *status,
+        Err(err) => Status::internal(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::http::{HeaderMap, StatusCode};
+    use std::convert::Infallible;
+
+    /// Echoes the request message twice, then ends with an OK status.
+    async fn echo(grpc_request: HttpRequest<Body>) -> Result<HttpResponse<Body>, Infallible> {
+        assert_eq!(grpc_request.headers()[CONTENT_TYPE], GRPC_CONTENT_TYPE);
+        assert!(!grpc_request.headers().contains_key(GRPC_ENCODING_HEADER));
+        let message = hyper::body::to_bytes(grpc_request.into_body())
+            .await
+            .unwrap();
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            // NOTE: split the second frame across chunks
+            sender.send_data(message.clone()).await?;
+            sender.send_data(message.slice(..3)).await?;
+            sender.send_data(message.slice(3..)).await?;
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            sender.send_trailers(trailers).await
+        });
+        Ok(HttpResponse::new(body))
+    }
+
+    fn grpc_web_request(content_type: &'static str, body: Vec<u8>) -> HttpRequest<Body> {
+        HttpRequest::builder()
+            .method(Method::POST)
+            .header(CONTENT_TYPE, content_type)
+            .body(body.into())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_serve_grpc_web() -> Result<(), Error> {
+        let mut service = GrpcWebLayer::new().layer(tower::service_fn(echo));
+
+        let http_response = service
+            .call(grpc_web_request(
+                "application/grpc-web-text",
+                b"AAAAAAcKBVRvbmlj".to_vec(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            http_response.headers()[CONTENT_TYPE],
+            "application/grpc-web-text+proto"
+        );
+        assert_eq!(
+            hyper::body::to_bytes(http_response.into_body())
+                .await
+                .unwrap(),
+            "AAAAAAcKBVRvbmlj\
+             AAAAAAcKBVRvbmlj\
+             gAAAAA9ncnBjLXN0YXR1czowDQo="
+        );
+
+        let message = Encoding::Gzip.compress(&[10, 5, 84, 111, 110, 105, 99])?;
+        let mut body = vec![1, 0, 0, 0, message.len() as u8];
+        body.extend(message);
+        let mut http_request = grpc_web_request("application/grpc-web", body);
+        http_request
+            .headers_mut()
+            .insert(GRPC_ENCODING_HEADER, HeaderValue::from_static("gzip"));
+        let http_response = service.call(http_request).await.unwrap();
+        assert_eq!(
+            hyper::body::to_bytes(http_response.into_body())
+                .await
+                .unwrap()[..12],
+            [0, 0, 0, 0, 7, 10, 5, 84, 111, 110, 105, 99]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_answer_cors() {
+        let mut service = GrpcWebLayer::new()
+            .cors(Cors::default())
+            .layer(tower::service_fn(echo));
+
+        let http_request = HttpRequest::builder()
+            .method(Method::OPTIONS)
+            .header(ORIGIN, "https://example.com")
+            .header("access-control-request-method", "POST")
+            .body(Body::empty())
+            .unwrap();
+        let http_response = service.call(http_request).await.unwrap();
+        assert_eq!(http_response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(http_response.headers()["access-control-allow-origin"], "*");
+
+        let mut http_request =
+            grpc_web_request("application/grpc-web-text", b"AAAAAAcKBVRvbmlj".to_vec());
+        http_request
+            .headers_mut()
+            .insert(ORIGIN, HeaderValue::from_static("https://example.com"));
+        let http_response = service.call(http_request).await.unwrap();
+        assert_eq!(http_response.headers()["access-control-allow-origin"], "*");
+    }
+
+    #[tokio::test]
+    async fn should_limit_message_size() -> Result<(), Error> {
+        let mut service = GrpcWebLayer::new()
+            .max_message_size(2)
+            .layer(tower::service_fn(echo));
+
+        let message = Encoding::Gzip.compress(&[10, 5, 84, 111, 110, 105, 99])?;
+        let mut body = vec![1, 0, 0, 0, message.len() as u8];
+        body.extend(message);
+        let mut http_request = grpc_web_request("application/grpc-web", body);
+        http_request
+            .headers_mut()
+            .insert(GRPC_ENCODING_HEADER, HeaderValue::from_static("gzip"));
+        let http_response = service.call(http_request).await.unwrap();
+        assert_eq!(http_response.headers()["grpc-status"], "8");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_reject_invalid_requests() {
+        let mut service = GrpcWebLayer::new().layer(tower::service_fn(echo));
+
+        let http_response = service
+            .call(grpc_web_request("application/grpc-web-text", b"!".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(http_response.status(), StatusCode::OK);
+        assert_eq!(http_response.headers()["grpc-status"], "3");
+    }
+
+    #[tokio::test]
+    async fn should_pass_through_grpc() {
+        let mut service = GrpcWebLayer::new().layer(tower::service_fn(|_| async {
+            Ok::<_, Infallible>(HttpResponse::new(Body::from("grpc")))
+        }));
+
+        let http_response = service
+            .call(grpc_web_request("application/grpc", vec![]))
+            .await
+            .unwrap();
+        assert!(!http_response.headers().contains_key(CONTENT_TYPE));
+        assert_eq!(
+            hyper::body::to_bytes(http_response.into_body())
+                .await
+                .unwrap(),
+            "grpc"
+        );
+    }
+}
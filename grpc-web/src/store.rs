@@ -0,0 +1,298 @@
+use crate::{Error, Metadata};
+use arc_swap::ArcSwap;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tonic::transport::Endpoint;
+
+/// Shortest time between refreshes caused by calls to unknown methods.
+pub const DEFAULT_MISS_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Metadata shared by every clone of a proxy, which is replaced atomically
+/// when the upstream is reflected again.
+#[derive(Clone)]
+pub struct MetadataStore {
+    inner: Arc<Inner>,
+    miss_refresh_interval: Duration,
+}
+
+struct Inner {
+    metadata: ArcSwap<Metadata>,
+    /// Whether the metadata came from reflection, rather than descriptors.
+    reflected: bool,
+    /// Replicas of the upstream to reflect again, which change as they are
+    /// discovered.
+    endpoints: ArcSwap<Vec<Endpoint>>,
+    /// Set once the upstream services have been discovered.
+    ready: (watch::Sender<bool>, watch::Receiver<bool>),
+    last_miss_refresh: Mutex<Option<Instant>>,
+}
+
+impl MetadataStore {
+    /// Metadata which is never refreshed, e.g. loaded from descriptors.
+    pub fn new(metadata: Metadata) -> Self {
+        Self::build(metadata, None, true)
+    }
+
+    /// Reflect the services of the upstream replicas at `endpoints`, which
+    /// are asked again on every refresh. Only the first replica to answer is
+    /// reflected.
+    pub async fn from_reflection_service(endpoints: Vec<Endpoint>) -> Result<Self, Error> {
+        let metadata = reflect(&endpoints).await?;
+        Ok(Self::with_endpoints(metadata, endpoints))
+    }
+
+    /// Services of the upstream replicas at `endpoints`, which have not been
+    /// discovered yet. The store becomes ready on the first successful
+    /// refresh.
+    pub fn pending(endpoints: Vec<Endpoint>) -> Self {
+        Self::build(Metadata::default(), Some(endpoints), false)
+    }
+
+    pub(crate) fn with_endpoints(metadata: Metadata, endpoints: Vec<Endpoint>) -> Self {
+        Self::build(metadata, Some(endpoints), true)
+    }
+
+    fn build(metadata: Metadata, endpoints: Option<Vec<Endpoint>>, ready: bool) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                metadata: ArcSwap::from_pointee(metadata),
+                reflected: endpoints.is_some(),
+                endpoints: ArcSwap::from_pointee(endpoints.unwrap_or_default()),
+                ready: watch::channel(ready),
+                last_miss_refresh: Mutex::new(None),
+            }),
+            miss_refresh_interval: DEFAULT_MISS_REFRESH_INTERVAL,
+        }
+    }
+
+    pub fn miss_refresh_interval(mut self, interval: Duration) -> Self {
+        self.miss_refresh_interval = interval;
+        self
+    }
+
+    pub fn load(&self) -> Arc<Metadata> {
+        self.inner.metadata.load_full()
+    }
+
+    /// Whether the upstream services have been discovered.
+    pub fn is_ready(&self) -> bool {
+        *self.inner.ready.1.borrow()
+    }
+
+    /// Wait until the upstream services have been discovered.
+    pub async fn ready(&self) {
+        let mut ready = self.inner.ready.1.clone();
+        while !*ready.borrow() {
+            if ready.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Mark the store as ready with the metadata it has, e.g. when every
+    /// method is forwarded anyway.
+    pub fn set_ready(&self) {
+        if !self.is_ready() {
+            // NOTE: cannot fail while the store holds a receiver
+            let _ = self.inner.ready.0.send(true);
+        }
+    }
+
+    /// Reflect the replicas at `endpoints` from now on.
+    pub(crate) fn set_endpoints(&self, endpoints: Vec<Endpoint>) {
+        self.inner.endpoints.store(Arc::new(endpoints));
+    }
+
+    /// Reflect the upstream again and swap in the result. In-flight calls
+    /// keep the metadata they started with.
+    pub async fn refresh(&self) -> Result<(), Error> {
+        if !self.inner.reflected {
+            log::debug!("Metadata was not reflected, nothing to refresh");
+            return Ok(());
+        }
+
+        let metadata = Arc::new(reflect(&self.inner.endpoints.load()).await?);
+        let previous = self.inner.metadata.swap(metadata.clone());
+        if self.is_ready() {
+            log_changes(&previous, &metadata);
+        } else {
+            log::info!(
+                "Found {} methods, upstream is ready",
+                metadata.methods().count()
+            );
+            self.set_ready();
+        }
+        Ok(())
+    }
+
+    /// Refresh after a call to an unknown method, at most once per
+    /// `miss_refresh_interval`. Returns whether the metadata was refreshed.
+    pub async fn refresh_on_miss(&self) -> bool {
+        if !self.inner.reflected {
+            return false;
+        }
+        {
+            let mut last_miss_refresh = self.inner.last_miss_refresh.lock().unwrap();
+            let now = Instant::now();
+            if let Some(last) = *last_miss_refresh {
+                if now.duration_since(last) < self.miss_refresh_interval {
+                    return false;
+                }
+            }
+            *last_miss_refresh = Some(now);
+        }
+
+        match self.refresh().await {
+            Ok(()) => true,
+            Err(err) => {
+                log::warn!("Unable to refresh metadata: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Refresh every `period` in the background, until every clone of this
+    /// store has been dropped.
+    pub fn refresh_every(&self, period: Duration) -> JoinHandle<()> {
+        let inner = Arc::downgrade(&self.inner);
+        let miss_refresh_interval = self.miss_refresh_interval;
+        tokio::spawn(async move {
+            let start = tokio::time::Instant::now() + period;
+            let mut interval = tokio::time::interval_at(start, period);
+            loop {
+                interval.tick().await;
+                let store = match inner.upgrade() {
+                    Some(inner) => MetadataStore {
+                        inner,
+                        miss_refresh_interval,
+                    },
+                    None => return,
+                };
+                if let Err(err) = store.refresh().await {
+                    log::warn!("Unable to refresh metadata: {}", err);
+                }
+            }
+        })
+    }
+}
+
+/// Reflect the first of `endpoints` to succeed. Otherwise errors from
+/// replicas which answered, e.g. without a reflection service, are returned
+/// over those which could not be reached.
+async fn reflect(endpoints: &[Endpoint]) -> Result<Metadata, Error> {
+    let mut error = Error::NoHealthyReplicas;
+    for endpoint in endpoints {
+        match Metadata::from_reflection_service(endpoint.clone()).await {
+            Ok(metadata) => return Ok(metadata),
+            Err(err) => {
+                log::debug!("Unable to reflect {}: {}", endpoint.uri(), err);
+                if let Error::NoHealthyReplicas | Error::TransportError(_) = error {
+                    error = err;
+                }
+            }
+        }
+    }
+    Err(error)
+}
+
+fn log_changes(previous: &Metadata, current: &Metadata) {
+    let previous: HashMap<_, _> = previous.methods().collect();
+    let current: HashMap<_, _> = current.methods().collect();
+    let mut changes: Vec<String> = current
+        .iter()
+        .filter_map(|(path, connection_type)| match previous.get(path) {
+            None => Some(format!("added {} ({:?})", path, connection_type)),
+            Some(previous) if previous != connection_type => Some(format!(
+                "changed {} ({:?} -> {:?})",
+                path, previous, connection_type
+            )),
+            Some(_) => None,
+        })
+        .chain(
+            previous
+                .keys()
+                .filter(|path| !current.contains_key(*path))
+                .map(|path| format!("removed {}", path)),
+        )
+        .collect();
+    changes.sort();
+
+    if changes.is_empty() {
+        log::info!("Refreshed metadata, no changes");
+    } else {
+        log::info!("Refreshed metadata: {}", changes.join(", "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{method, serve_reflection, FakeReflection};
+    use crate::{ConnectionType, ReflectionVersion};
+    use hyper::http::uri::PathAndQuery;
+
+    #[tokio::test]
+    async fn should_refresh_on_miss() -> Result<(), Error> {
+        let reflection = FakeReflection::new(vec![method("SayHello", false)]);
+        let dst = serve_reflection(ReflectionVersion::V1, reflection.clone()).await;
+        let store =
+            MetadataStore::from_reflection_service(vec![Endpoint::from_shared(dst).unwrap()])
+                .await?
+                .miss_refresh_interval(Duration::from_secs(60));
+
+        let path = PathAndQuery::from_static("/helloworld.Greeter/SayGoodbye");
+        assert!(store.load().get_query_type(path.clone()).is_err());
+
+        reflection.0.lock().unwrap().service[0]
+            .method
+            .push(method("SayGoodbye", true));
+        assert!(store.refresh_on_miss().await);
+        assert_eq!(
+            store.load().get_query_type(path)?,
+            ConnectionType::ServerStreaming
+        );
+
+        // NOTE: rate-limited until the interval has passed
+        assert!(!store.refresh_on_miss().await);
+        assert!(
+            !MetadataStore::new(Metadata::default())
+                .refresh_on_miss()
+                .await
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_refresh_periodically() -> Result<(), Error> {
+        let reflection = FakeReflection::new(vec![method("SayHello", false)]);
+        let dst = serve_reflection(ReflectionVersion::V1Alpha, reflection.clone()).await;
+        // NOTE: replicas which do not answer are skipped
+        let store = MetadataStore::from_reflection_service(vec![
+            Endpoint::from_static("http://127.0.0.1:1"),
+            Endpoint::from_shared(dst).unwrap(),
+        ])
+        .await?;
+        let task = store.refresh_every(Duration::from_millis(10));
+
+        reflection.0.lock().unwrap().service[0]
+            .method
+            .push(method("SayGoodbye", false));
+        let path = PathAndQuery::from_static("/helloworld.Greeter/SayGoodbye");
+        while store.load().get_query_type(path.clone()).is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // NOTE: the task stops once the store is dropped
+        drop(store);
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("refresh task did not stop")
+            .unwrap();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use crate::metadata::proto;
+use crate::ReflectionVersion;
+use futures::stream;
+use prost::Message;
+use prost_types::{FileDescriptorProto, MethodDescriptorProto, ServiceDescriptorProto};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tonic::Request as GrpcRequest;
+use tonic::{Code, Status};
+
+/// Path in the temporary directory, unique to this process.
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("grpc-web-{}-{}", std::process::id(), name))
+}
+
+/// Unary or server streaming method called `name`.
+pub(crate) fn method(name: &str, server_streaming: bool) -> MethodDescriptorProto {
+    MethodDescriptorProto {
+        name: Some(name.to_string()),
+        server_streaming: Some(server_streaming).filter(|streaming| *streaming),
+        ..Default::default()
+    }
+}
+
+/// Reflection service describing the `helloworld` package, with its
+/// messages in a dependency that is only sent when asked for by name.
+/// The services may be changed while it is running.
+#[derive(Clone)]
+pub(crate) struct FakeReflection(pub(crate) Arc<Mutex<FileDescriptorProto>>);
+
+impl FakeReflection {
+    pub(crate) fn new(methods: Vec<MethodDescriptorProto>) -> Self {
+        Self(Arc::new(Mutex::new(FileDescriptorProto {
+            name: Some("greeter.proto".to_string()),
+            package: Some("helloworld".to_string()),
+            dependency: vec!["messages.proto".to_string()],
+            service: vec![ServiceDescriptorProto {
+                name: Some("Greeter".to_string()),
+                method: methods,
+                ..Default::default()
+            }],
+            ..Default::default()
+        })))
+    }
+}
+
+macro_rules! impl_fake_reflection {
+    ($version:ident) => {
+        #[tonic::async_trait]
+        impl proto::$version::server_reflection_server::ServerReflection for FakeReflection {
+            type ServerReflectionInfoStream = stream::Iter<
+                std::vec::IntoIter<Result<proto::$version::ServerReflectionResponse, Status>>,
+            >;
+
+            async fn server_reflection_info(
+                &self,
+                request: GrpcRequest<tonic::Streaming<proto::$version::ServerReflectionRequest>>,
+            ) -> Result<tonic::Response<Self::ServerReflectionInfoStream>, Status> {
+                use proto::$version::server_reflection_request::MessageRequest;
+                use proto::$version::server_reflection_response::MessageResponse;
+                use proto::$version::*;
+
+                let messages = FileDescriptorProto {
+                    name: Some("messages.proto".to_string()),
+                    package: Some("helloworld".to_string()),
+                    ..Default::default()
+                };
+                let greeter = self.0.lock().unwrap().clone();
+                let encode = |files: Vec<FileDescriptorProto>| {
+                    MessageResponse::FileDescriptorResponse(FileDescriptorResponse {
+                        file_descriptor_proto: files
+                            .iter()
+                            .map(|file| {
+                                let mut buf = Vec::new();
+                                file.encode(&mut buf).unwrap();
+                                buf
+                            })
+                            .collect(),
+                    })
+                };
+
+                let mut inbound = request.into_inner();
+                let mut responses = Vec::new();
+                while let Some(request) = inbound.message().await? {
+                    let message_response = match request.message_request.clone() {
+                        Some(MessageRequest::ListServices(_)) => {
+                            MessageResponse::ListServicesResponse(ListServiceResponse {
+                                service: greeter
+                                    .service
+                                    .iter()
+                                    .map(|service| ServiceResponse {
+                                        name: format!("helloworld.{}", service.name()),
+                                    })
+                                    .collect(),
+                            })
+                        }
+                        Some(MessageRequest::FileContainingSymbol(symbol))
+                            if symbol.starts_with("helloworld.") =>
+                        {
+                            encode(vec![greeter.clone()])
+                        }
+                        Some(MessageRequest::FileByFilename(name)) if name == "messages.proto" => {
+                            encode(vec![messages.clone()])
+                        }
+                        _ => MessageResponse::ErrorResponse(ErrorResponse {
+                            error_code: Code::NotFound as i32,
+                            error_message: "not found".to_string(),
+                        }),
+                    };
+                    responses.push(Ok(ServerReflectionResponse {
+                        valid_host: String::new(),
+                        original_request: Some(request),
+                        message_response: Some(message_response),
+                    }));
+                }
+                Ok(tonic::Response::new(stream::iter(responses)))
+            }
+        }
+    };
+}
+
+impl_fake_reflection!(v1);
+impl_fake_reflection!(v1alpha);
+
+pub(crate) async fn serve_reflection(
+    version: ReflectionVersion,
+    reflection: FakeReflection,
+) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
+
+    let mut server = tonic::transport::Server::builder();
+    match version {
+        ReflectionVersion::V1 => tokio::spawn(
+            server
+                .add_service(
+                    proto::v1::server_reflection_server::ServerReflectionServer::new(reflection),
+                )
+                .serve_with_incoming(incoming),
+        ),
+        ReflectionVersion::V1Alpha => tokio::spawn(
+            server
+                .add_service(
+                    proto::v1alpha::server_reflection_server::ServerReflectionServer::new(
+                        reflection,
+                    ),
+                )
+                .serve_with_incoming(incoming),
+        ),
+    };
+    format!("http://{}", addr)
+}
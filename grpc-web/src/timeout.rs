@@ -0,0 +1,260 @@
+use crate::Error;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+use tonic::metadata::{AsciiMetadataValue, MetadataMap};
+use tonic::Status;
+
+pub const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+/// At most eight digits are allowed in a `grpc-timeout` value.
+const MAX_TIMEOUT_VALUE: u64 = 99_999_999;
+
+/// Parse a `grpc-timeout` value such as `500m` or `10S`, see
+/// https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-HTTP2.md#requests
+pub fn parse_grpc_timeout(value: &str) -> Result<Duration, Error> {
+    // NOTE: values are client input, a multi-byte last character must not
+    // make the split below panic
+    if value.len() < 2 || value.len() > 9 || !value.is_char_boundary(value.len() - 1) {
+        return Err(Error::InvalidTimeout);
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Error::InvalidTimeout);
+    }
+    let amount: u64 = digits.parse().map_err(|_| Error::InvalidTimeout)?;
+
+    match unit {
+        "H" => Ok(Duration::from_secs(amount * 60 * 60)),
+        "M" => Ok(Duration::from_secs(amount * 60)),
+        "S" => Ok(Duration::from_secs(amount)),
+        "m" => Ok(Duration::from_millis(amount)),
+        "u" => Ok(Duration::from_micros(amount)),
+        "n" => Ok(Duration::from_nanos(amount)),
+        _ => Err(Error::InvalidTimeout),
+    }
+}
+
+/// Format a duration as a `grpc-timeout` value, using the finest unit
+/// that fits in eight digits.
+pub fn format_grpc_timeout(timeout: Duration) -> String {
+    let nanos = timeout.as_nanos();
+    let units: [(u128, &str); 6] = [
+        (1, "n"),
+        (1_000, "u"),
+        (1_000_000, "m"),
+        (1_000_000_000, "S"),
+        (60 * 1_000_000_000, "M"),
+        (60 * 60 * 1_000_000_000, "H"),
+    ];
+    for (scale, unit) in units.iter() {
+        let amount = nanos / scale;
+        if amount <= MAX_TIMEOUT_VALUE as u128 {
+            return format!("{}{}", amount, unit);
+        }
+    }
+    format!("{}H", MAX_TIMEOUT_VALUE)
+}
+
+/// Fail with `DEADLINE_EXCEEDED` if the future has not completed by the
+/// deadline.
+pub async fn with_deadline<F, T>(deadline: Option<Instant>, future: F) -> Result<T, Status>
+where
+    F: Future<Output = Result<T, Status>>,
+{
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future)
+            .await
+            .unwrap_or_else(|_| Err(Status::deadline_exceeded("Deadline exceeded"))),
+        None => future.await,
+    }
+}
+
+/// Timeout applied when the client does not send one, and the longest
+/// timeout a client may ask for.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimeoutPolicy {
+    pub default: Option<Duration>,
+    pub max: Option<Duration>,
+}
+
+/// Proxy-wide timeout policy, with overrides for individual methods keyed
+/// by their path (e.g. `/helloworld.Greeter/SayHello`).
+#[derive(Debug, Clone, Default)]
+pub struct Timeouts {
+    policy: TimeoutPolicy,
+    methods: HashMap<String, TimeoutPolicy>,
+}
+
+impl Timeouts {
+    pub fn new(policy: TimeoutPolicy) -> Self {
+        Self {
+            policy,
+            methods: HashMap::new(),
+        }
+    }
+
+    pub fn with_method(mut self, path: impl Into<String>, policy: TimeoutPolicy) -> Self {
+        self.methods.insert(path.into(), policy);
+        self
+    }
+
+    /// Timeout for a call to `path` given the one requested by the client.
+    pub fn resolve(&self, path: &str, requested: Option<Duration>) -> Option<Duration> {
+        let policy = self.methods.get(path).unwrap_or(&self.policy);
+        match (requested.or(policy.default), policy.max) {
+            (Some(timeout), Some(max)) => Some(timeout.min(max)),
+            (timeout, max) => timeout.or(max),
+        }
+    }
+
+    /// Replace the `grpc-timeout` of a call to `path` with the resolved
+    /// timeout and return the deadline to enforce.
+    pub fn apply(&self, path: &str, metadata: &mut MetadataMap) -> Result<Option<Instant>, Error> {
+        let requested = match metadata.remove(GRPC_TIMEOUT_HEADER) {
+            Some(value) => Some(parse_grpc_timeout(
+                value.to_str().map_err(|_| Error::InvalidTimeout)?,
+            )?),
+            None => None,
+        };
+
+        let timeout = match self.resolve(path, requested) {
+            Some(timeout) => timeout,
+            None => return Ok(None),
+        };
+        let value = AsciiMetadataValue::from_str(&format_grpc_timeout(timeout))
+            .map_err(|_| Error::InvalidTimeout)?;
+        metadata.insert(GRPC_TIMEOUT_HEADER, value);
+        Ok(Some(Instant::now() + timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_grpc_timeout() -> Result<(), Error> {
+        assert_eq!(parse_grpc_timeout("2H")?, Duration::from_secs(7200));
+        assert_eq!(parse_grpc_timeout("3M")?, Duration::from_secs(180));
+        assert_eq!(parse_grpc_timeout("10S")?, Duration::from_secs(10));
+        assert_eq!(parse_grpc_timeout("500m")?, Duration::from_millis(500));
+        assert_eq!(parse_grpc_timeout("7u")?, Duration::from_micros(7));
+        assert_eq!(
+            parse_grpc_timeout("99999999n")?,
+            Duration::from_nanos(99_999_999)
+        );
+
+        for value in &[
+            "",
+            "S",
+            "10",
+            "10s",
+            "-1S",
+            "+1S",
+            "1.5S",
+            "123456789S",
+            "1é",
+            "é",
+        ] {
+            assert!(matches!(
+                parse_grpc_timeout(value),
+                Err(Error::InvalidTimeout)
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_format_grpc_timeout() -> Result<(), Error> {
+        assert_eq!(format_grpc_timeout(Duration::from_nanos(5)), "5n");
+        assert_eq!(format_grpc_timeout(Duration::from_millis(500)), "500000u");
+        assert_eq!(format_grpc_timeout(Duration::from_secs(30)), "30000000u");
+        assert_eq!(
+            format_grpc_timeout(Duration::from_secs(86_400)),
+            "86400000m"
+        );
+
+        let timeout = Duration::from_millis(1_234);
+        assert_eq!(parse_grpc_timeout(&format_grpc_timeout(timeout))?, timeout);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_resolve_timeouts() {
+        let timeouts = Timeouts::default();
+        assert_eq!(timeouts.resolve("/a/b", None), None);
+        assert_eq!(
+            timeouts.resolve("/a/b", Some(Duration::from_secs(1))),
+            Some(Duration::from_secs(1))
+        );
+
+        let timeouts = Timeouts::new(TimeoutPolicy {
+            default: Some(Duration::from_secs(10)),
+            max: Some(Duration::from_secs(60)),
+        })
+        .with_method(
+            "/a/stream",
+            TimeoutPolicy {
+                default: None,
+                max: Some(Duration::from_secs(600)),
+            },
+        );
+        assert_eq!(
+            timeouts.resolve("/a/b", None),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            timeouts.resolve("/a/b", Some(Duration::from_secs(120))),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            timeouts.resolve("/a/stream", None),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(
+            timeouts.resolve("/a/stream", Some(Duration::from_secs(120))),
+            Some(Duration::from_secs(120))
+        );
+    }
+
+    #[tokio::test]
+    async fn should_apply_timeouts() -> Result<(), Error> {
+        let timeouts = Timeouts::new(TimeoutPolicy {
+            default: None,
+            max: Some(Duration::from_secs(1)),
+        });
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert(GRPC_TIMEOUT_HEADER, "10S".parse().unwrap());
+        assert!(timeouts.apply("/a/b", &mut metadata)?.is_some());
+        assert_eq!(metadata.get(GRPC_TIMEOUT_HEADER).unwrap(), "1000000u");
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert(GRPC_TIMEOUT_HEADER, "soon".parse().unwrap());
+        assert!(matches!(
+            timeouts.apply("/a/b", &mut metadata),
+            Err(Error::InvalidTimeout)
+        ));
+
+        let mut metadata = MetadataMap::new();
+        assert!(Timeouts::default().apply("/a/b", &mut metadata)?.is_none());
+        assert!(metadata.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_expire_deadline() {
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let result: Result<(), Status> =
+            with_deadline(Some(deadline), futures::future::pending()).await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::DeadlineExceeded);
+
+        let result = with_deadline(None, async { Ok::<_, Status>(1) }).await;
+        assert_eq!(result.unwrap(), 1);
+    }
+}
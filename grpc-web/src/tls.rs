@@ -0,0 +1,255 @@
+use crate::Error;
+use rustls::internal::pemfile;
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tonic::transport::ClientTlsConfig;
+use webpki::DNSNameRef;
+
+/// Signature algorithms accepted in the certificate chain of the upstream,
+/// the same ones rustls accepts.
+static SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// TLS settings for connections to the upstream, both for forwarding calls
+/// and for asking its reflection service.
+#[derive(Clone, Default)]
+pub struct UpstreamTls {
+    ca_certificate: Option<Vec<u8>>,
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+    domain_name: Option<String>,
+    skip_domain_verification: bool,
+}
+
+impl UpstreamTls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// PEM encoded certificates of the authorities the upstream certificate
+    /// must be signed by, instead of the public ones trusted by default.
+    pub fn ca_certificate(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.ca_certificate = Some(pem.into());
+        self
+    }
+
+    /// PEM encoded certificate chain and private key to present to the
+    /// upstream, for mutual TLS.
+    pub fn identity(mut self, cert_pem: impl Into<Vec<u8>>, key_pem: impl Into<Vec<u8>>) -> Self {
+        self.identity = Some((cert_pem.into(), key_pem.into()));
+        self
+    }
+
+    /// Name sent as SNI and expected in the upstream certificate, instead of
+    /// the host of each replica. Needed for replicas addressed by IP.
+    pub fn domain_name(mut self, domain_name: impl Into<String>) -> Self {
+        self.domain_name = Some(domain_name.into());
+        self
+    }
+
+    /// Accept upstream certificates issued for any name, as long as they are
+    /// signed by the authorities. Only meant for development.
+    pub fn skip_domain_verification(mut self, skip: bool) -> Self {
+        self.skip_domain_verification = skip;
+        self
+    }
+
+    pub(crate) fn client_tls_config(&self) -> Result<ClientTlsConfig, Error> {
+        let mut config = ClientConfig::new();
+        config.set_protocols(&[b"h2".to_vec()]);
+
+        match &self.ca_certificate {
+            Some(ca_certificate) => {
+                let (added, _) = config
+                    .root_store
+                    .add_pem_file(&mut Cursor::new(ca_certificate))
+                    .map_err(|_| {
+                        Error::InvalidTlsConfig("invalid upstream CA certificate".to_string())
+                    })?;
+                if added == 0 {
+                    return Err(Error::InvalidTlsConfig(
+                        "no upstream CA certificate".to_string(),
+                    ));
+                }
+            }
+            // NOTE: the Mozilla root program, as browsers would trust
+            None => config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+        }
+
+        if let Some((cert_pem, key_pem)) = &self.identity {
+            let certs = pemfile::certs(&mut Cursor::new(cert_pem))
+                .map_err(|_| Error::InvalidTlsConfig("invalid client certificate".to_string()))?;
+            let key = private_key(key_pem)?;
+            config
+                .set_single_client_cert(certs, key)
+                .map_err(|err| Error::InvalidTlsConfig(err.to_string()))?;
+        }
+
+        if self.skip_domain_verification {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(SkipDomainVerification));
+        }
+
+        let mut tls = ClientTlsConfig::new().rustls_client_config(config);
+        if let Some(domain_name) = &self.domain_name {
+            tls = tls.domain_name(domain_name.clone());
+        }
+        Ok(tls)
+    }
+}
+
+/// First PKCS#8 key, falling back to PKCS#1 RSA keys.
+fn private_key(pem: &[u8]) -> Result<rustls::PrivateKey, Error> {
+    let invalid_key = || Error::InvalidTlsConfig("invalid client private key".to_string());
+    let mut keys = pemfile::pkcs8_private_keys(&mut Cursor::new(pem)).map_err(|_| invalid_key())?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut Cursor::new(pem)).map_err(|_| invalid_key())?;
+    }
+    keys.into_iter().next().ok_or_else(invalid_key)
+}
+
+/// Verifies the certificate chain of the upstream like rustls does, without
+/// checking the name it was issued for.
+struct SkipDomainVerification;
+
+impl ServerCertVerifier for SkipDomainVerification {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let (cert, chain) = presented_certs
+            .split_first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        let cert = webpki::EndEntityCert::from(&cert.0).map_err(TLSError::WebPKIError)?;
+        let chain: Vec<&[u8]> = chain.iter().map(|cert| cert.0.as_ref()).collect();
+        let trust_anchors: Vec<_> = roots
+            .roots
+            .iter()
+            .map(|root| root.to_trust_anchor())
+            .collect();
+        let now = webpki::Time::try_from(SystemTime::now())
+            .map_err(|_| TLSError::FailedToGetCurrentTime)?;
+        cert.verify_is_valid_tls_server_cert(
+            SIGNATURE_ALGORITHMS,
+            &webpki::TLSServerTrustAnchors(&trust_anchors),
+            &chain,
+            now,
+        )
+        .map_err(TLSError::WebPKIError)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::proto::v1::server_reflection_server::ServerReflectionServer;
+    use crate::test_util::{method, FakeReflection};
+    use crate::{GrpcWebProxy, Metadata};
+    use tonic::transport::{Endpoint, Identity, Server, ServerTlsConfig};
+
+    fn testdata(name: &str) -> Vec<u8> {
+        std::fs::read(format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+    }
+
+    /// Reflection service requiring clients to present a certificate signed
+    /// by the test authority.
+    async fn serve_tls() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
+        let tls = ServerTlsConfig::new()
+            .identity(Identity::from_pem(
+                testdata("second.pem"),
+                testdata("second.key"),
+            ))
+            .client_ca_root(tonic::transport::Certificate::from_pem(testdata("ca.pem")));
+        let reflection = FakeReflection::new(vec![method("SayHello", false)]);
+        tokio::spawn(
+            Server::builder()
+                .tls_config(tls)
+                .unwrap()
+                .add_service(ServerReflectionServer::new(reflection))
+                .serve_with_incoming(incoming),
+        );
+        format!("https://{}", addr)
+    }
+
+    async fn reflect(dst: &str, tls: UpstreamTls) -> Result<Metadata, Error> {
+        let endpoint = Endpoint::from_shared(dst.to_string())
+            .unwrap()
+            .tls_config(tls.client_tls_config()?)?;
+        Metadata::from_reflection_service(endpoint).await
+    }
+
+    #[tokio::test]
+    async fn should_connect_with_client_certificate() -> Result<(), Error> {
+        let dst = serve_tls().await;
+        let tls = UpstreamTls::new()
+            .ca_certificate(testdata("ca.pem"))
+            .domain_name("localhost");
+
+        assert!(reflect(&dst, tls.clone()).await.is_err());
+
+        let tls = tls.identity(testdata("first.pem"), testdata("first.key"));
+        assert!(reflect(&dst, tls.clone()).await?.methods().count() > 0);
+        GrpcWebProxy::builder()
+            .tls(tls.clone())
+            .connect(dst.clone())
+            .await?;
+
+        // NOTE: the certificate is issued for localhost and 127.0.0.1 only
+        let tls = tls.domain_name("greeter.test");
+        assert!(reflect(&dst, tls.clone()).await.is_err());
+        assert!(
+            reflect(&dst, tls.skip_domain_verification(true))
+                .await?
+                .methods()
+                .count()
+                > 0
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_trust_public_authorities_by_default() -> Result<(), Error> {
+        let dst = serve_tls().await;
+        let tls = UpstreamTls::new()
+            .identity(testdata("first.pem"), testdata("first.key"))
+            .domain_name("localhost");
+        assert!(tls.client_tls_config().is_ok());
+        // NOTE: the test authority is not a public one
+        assert!(reflect(&dst, tls).await.is_err());
+
+        assert!(matches!(
+            UpstreamTls::new()
+                .ca_certificate("not a certificate")
+                .client_tls_config(),
+            Err(Error::InvalidTlsConfig(_))
+        ));
+
+        Ok(())
+    }
+}